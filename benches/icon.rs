@@ -0,0 +1,47 @@
+//! Measures how much the decoded icon cache saves each time a button's icon is loaded.
+//!
+//! Run with `cargo bench --bench icon`. This needs a working gdk-pixbuf, but not a display.
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use niri_taskbar::icon;
+use waybar_cffi::gtk::gdk_pixbuf::{Colorspace, Pixbuf};
+
+const ITERATIONS: u32 = 200;
+
+fn main() {
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let path = dir.path().join("icon.png");
+    Pixbuf::new(Colorspace::Rgb, true, 8, 512, 512)
+        .expect("pixbuf")
+        .savev(&path, "png", &[])
+        .expect("saved icon");
+
+    let uncached = measure(&path, icon::clear_pixbufs);
+    let cached = measure(&path, || {});
+
+    println!("uncached: {uncached:?} per load");
+    println!("cached:   {cached:?} per load");
+    println!(
+        "speedup:  {:.1}x",
+        uncached.as_secs_f64() / cached.as_secs_f64()
+    );
+}
+
+// Loads the icon the way a 24 pixel button at scale 2 would, calling `before` ahead of each load.
+fn measure(path: &Path, before: impl Fn()) -> Duration {
+    icon::pixbuf(path, 24, 2).expect("icon loads");
+
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        before();
+        let start = Instant::now();
+        std::hint::black_box(icon::pixbuf(path, 24, 2));
+        total += start.elapsed();
+    }
+
+    total / ITERATIONS
+}
@@ -1,19 +1,42 @@
-use std::{cell::RefCell, fmt::Debug, path::PathBuf};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
 
 use waybar_cffi::gtk::{
-    self as gtk, Border, CssProvider, IconLookupFlags, IconSize, IconTheme, ReliefStyle,
+    self as gtk, Align, Border, CssProvider, IconLookupFlags, IconSize, IconTheme, ReliefStyle,
     StateFlags,
     gdk_pixbuf::Pixbuf,
-    prelude::{ButtonExt, CssProviderExt, GdkPixbufExt, IconThemeExt, StyleContextExt, WidgetExt},
+    glib,
+    prelude::{
+        ButtonExt, ContainerExt, CssProviderExt, GdkPixbufExt, IconThemeExt, ImageExt, LabelExt,
+        OverlayExt, PopoverExt, ProgressBarExt, StyleContextExt, WidgetExt,
+    },
+};
+
+use crate::{
+    icon,
+    model::{ButtonModel, Tooltip},
+    state::State,
 };
 
-use crate::state::State;
+/// How long the pointer has to stay over a button before its window preview is refreshed.
+const PREVIEW_DELAY: Duration = Duration::from_millis(300);
 
-/// A taskbar button.
+/// A taskbar button, which renders a [`ButtonModel`].
 pub struct Button {
     app_id: Option<String>,
+    badge: gtk::Label,
     button: gtk::Button,
+    classes: RefCell<Vec<String>>,
+    icon_path: Rc<RefCell<Option<PathBuf>>>,
+    overlay: gtk::Overlay,
+    progress: gtk::ProgressBar,
     state: State,
+    tooltip: RefCell<Option<Tooltip>>,
 }
 
 impl Debug for Button {
@@ -42,10 +65,17 @@ thread_local! {
     }
 }
 
+/// Adds the base taskbar CSS to the given style context.
+pub fn add_style_provider(context: &gtk::StyleContext) {
+    BUTTON_CSS_PROVIDER.with(|provider| {
+        context.add_provider(provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
+    });
+}
+
 impl Button {
     /// Instantiates a new button, including creating a new Gtk button internally.
-    #[tracing::instrument(level = "TRACE", fields(app_id = &window.app_id))]
-    pub fn new(state: &State, window: &niri_ipc::Window) -> Self {
+    #[tracing::instrument(level = "TRACE", fields(app_id = &model.app_id))]
+    pub fn new(state: &State, model: &ButtonModel) -> Self {
         let state = state.clone();
 
         // Set up the basic image button.
@@ -58,92 +88,234 @@ impl Button {
         button.set_relief(ReliefStyle::None);
 
         // Provide the base CSS for each button that users can then extend.
-        BUTTON_CSS_PROVIDER.with(|provider| {
-            button
-                .style_context()
-                .add_provider(provider, gtk::STYLE_PROVIDER_PRIORITY_APPLICATION);
-        });
-
-        let app_id = window.app_id.clone();
-        let icon_path = app_id
-            .as_deref()
-            .and_then(|id| state.icon_cache().lookup(id));
+        add_style_provider(&button.style_context());
+
+        // Badges and progress bars are drawn over the button, so that they don't change its size.
+        // They're only shown when set, so they need to be left alone when the container is shown.
+        let badge = gtk::Label::new(None);
+        badge.set_halign(Align::End);
+        badge.set_valign(Align::Start);
+        badge.set_no_show_all(true);
+        add_style_provider(&badge.style_context());
+        badge.style_context().add_class("badge");
+
+        let progress = gtk::ProgressBar::new();
+        progress.set_valign(Align::End);
+        progress.set_no_show_all(true);
+        add_style_provider(&progress.style_context());
+
+        let overlay = gtk::Overlay::new();
+        overlay.add(&button);
+        overlay.add_overlay(&badge);
+        overlay.add_overlay(&progress);
+        overlay.set_overlay_pass_through(&badge, true);
+        overlay.set_overlay_pass_through(&progress, true);
 
         let button = Self {
-            app_id,
+            app_id: model.app_id.clone(),
+            badge,
             button,
+            classes: Default::default(),
+            icon_path: Default::default(),
+            overlay,
+            progress,
             state,
+            tooltip: Default::default(),
         };
 
         // Set up our event handlers. It's easier to do this with self already available.
-        button.connect_click_handler(window.id);
-        button.connect_size_allocate(icon_path);
+        button.connect_click_handler(model.id);
+        button.connect_size_allocate();
+        if button.state.config().previews_enabled() {
+            button.connect_preview(model.id);
+        }
 
+        button.update(model);
         button
     }
 
-    /// Sets whether the window represented by this button is currently focused.
-    #[tracing::instrument(level = "TRACE")]
-    pub fn set_focus(&self, focus: bool) {
+    /// Updates the button to match the given model.
+    ///
+    /// Only the parts of the model that have changed are touched, since some of them (such as the
+    /// icon) are relatively expensive to redraw.
+    #[tracing::instrument(level = "TRACE", skip(model))]
+    pub fn update(&self, model: &ButtonModel) {
+        // We track what we applied, rather than asking the model what might have been applied,
+        // since the classes can change between models in ways that we can't otherwise know.
         let context = self.button.style_context();
+        for class in self.classes.borrow().iter() {
+            if !model.classes.contains(class) {
+                context.remove_class(class);
+            }
+        }
+        for class in model.classes.iter() {
+            context.add_class(class);
+        }
+        self.classes.replace(model.classes.clone());
 
-        if focus {
-            context.add_class("focused");
-            context.remove_class("urgent");
-        } else {
-            context.remove_class("focused");
+        // Hints are shown in place of the label while they're active.
+        let label = model
+            .hint
+            .as_deref()
+            .or(model.label.as_deref())
+            .unwrap_or_default();
+        if self.button.label().as_deref().unwrap_or_default() != label {
+            self.button.set_label(label);
         }
-    }
 
-    /// Sets the window title.
-    #[tracing::instrument(level = "TRACE")]
-    pub fn set_title(&self, title: Option<&str>) {
-        self.button.set_tooltip_text(title);
-
-        // Apply any app styling rules.
-        if let Some(app_id) = &self.app_id {
-            if let Some(title) = title {
-                let config = self.state.config();
-                let context = self.button.style_context();
-
-                // First, remove all the possible classes for this app.
-                for class in config.app_classes(app_id) {
-                    context.remove_class(class);
+        if *self.icon_path.borrow() != model.icon {
+            self.icon_path.replace(model.icon.clone());
+
+            // Removing the image forces the size allocation handler to redraw the icon.
+            self.button.set_image(None::<&gtk::Widget>);
+            self.button.queue_resize();
+        }
+
+        match &model.badge {
+            Some(badge) => {
+                if self.badge.text() != badge.as_str() {
+                    self.badge.set_text(badge);
                 }
+                self.badge.show();
+            }
+            None => self.badge.hide(),
+        }
 
-                // Now add the classes that actually do match.
-                for class in config.app_matches(app_id, title) {
-                    context.add_class(class);
+        match model.progress {
+            Some(fraction) => {
+                if self.progress.fraction() != fraction {
+                    self.progress.set_fraction(fraction);
                 }
+                self.progress.show();
             }
+            None => self.progress.hide(),
         }
-    }
 
-    /// Sets the window to urgent: that is, needing attention.
-    ///
-    /// This state is automatically cleared the next time the window is focused.
-    #[tracing::instrument(level = "TRACE")]
-    pub fn set_urgent(&self) {
-        self.button.style_context().add_class("urgent");
+        if *self.tooltip.borrow() != model.tooltip {
+            match &model.tooltip {
+                Some(Tooltip::Markup(markup)) => self.button.set_tooltip_markup(Some(markup)),
+                Some(Tooltip::Text(text)) => self.button.set_tooltip_text(Some(text)),
+                None => self.button.set_tooltip_text(None),
+            }
+            self.tooltip.replace(model.tooltip.clone());
+        }
     }
 
-    /// Returns the actual [`gtk::Button`] widget.
-    pub fn widget(&self) -> &gtk::Button {
-        &self.button
+    /// Returns the widget to add to the taskbar, which contains the actual [`gtk::Button`].
+    pub fn widget(&self) -> &gtk::Overlay {
+        &self.overlay
     }
 
     fn connect_click_handler(&self, window_id: u64) {
         let state = self.state.clone();
 
         self.button.connect_clicked(move |_| {
-            if let Err(e) = state.niri().activate_window(window_id) {
-                tracing::warn!(%e, id = window_id, "error trying to activate window");
+            // Niri may be slow to respond, so we don't wait for it before returning to the main
+            // loop.
+            let state = state.clone();
+            glib::spawn_future_local(async move {
+                if let Err(e) = state.niri().activate_window(window_id).await {
+                    tracing::warn!(%e, id = window_id, "error trying to activate window");
+                }
+            });
+        });
+    }
+
+    fn connect_preview(&self, window_id: u64) {
+        let image = gtk::Image::new();
+        let popover = gtk::Popover::new(Some(&self.button));
+        popover.set_modal(false);
+        popover.style_context().add_class("niri-taskbar-preview");
+        popover.add(&image);
+
+        // Captures complete asynchronously, so we need to know if the pointer is still over the
+        // button by the time the preview is available. The crossing count goes up every time the
+        // pointer enters or leaves, so a delayed capture can tell whether the pointer has stayed
+        // put since it was scheduled.
+        let crossings = Rc::new(Cell::new(0_u64));
+
+        self.button.connect_enter_notify_event({
+            let crossings = crossings.clone();
+            let popover = popover.clone();
+            let state = self.state.clone();
+
+            move |button, _| {
+                let hover = crossings.get() + 1;
+                crossings.set(hover);
+                let config = state.config();
+                let size = config.previews_size();
+                let refresh = config.previews_refresh();
+
+                // Show whatever we captured last time straight away, then refresh it.
+                if let Some(path) = state.previews().cached(window_id) {
+                    Self::show_preview(&popover, &image, button, &path, size);
+                }
+
+                let button = button.clone();
+                let crossings = crossings.clone();
+                let image = image.clone();
+                let popover = popover.clone();
+                let state = state.clone();
+                glib::spawn_future_local(async move {
+                    // Niri copies every screenshot to the clipboard, so don't capture anything if
+                    // the pointer is just passing over the button.
+                    glib::timeout_future(PREVIEW_DELAY).await;
+                    if crossings.get() != hover {
+                        return;
+                    }
+
+                    match state.previews().refresh(window_id, refresh).await {
+                        Some(Ok(path)) if crossings.get() == hover => {
+                            Self::show_preview(&popover, &image, &button, &path, size);
+                        }
+                        Some(Ok(_)) | None => {}
+                        Some(Err(e)) => {
+                            tracing::debug!(%e, id = window_id, "cannot capture window preview");
+                        }
+                    }
+                });
+
+                glib::Propagation::Proceed
             }
         });
+
+        self.button.connect_leave_notify_event(move |_, _| {
+            crossings.set(crossings.get() + 1);
+            popover.popdown();
+
+            glib::Propagation::Proceed
+        });
+    }
+
+    fn show_preview(
+        popover: &gtk::Popover,
+        image: &gtk::Image,
+        button: &gtk::Button,
+        path: &Path,
+        size: i32,
+    ) {
+        let size = size * button.scale_factor();
+
+        // Previews change every time they're captured, so there's no point going through the
+        // icon pixbuf cache here.
+        let surface = match Pixbuf::from_file_at_scale(path, size, size, true) {
+            Ok(pixbuf) => pixbuf.create_surface(0, button.window().as_ref()),
+            Err(e) => {
+                tracing::info!(%e, ?path, "cannot load window preview");
+                None
+            }
+        };
+
+        if let Some(surface) = surface {
+            image.set_from_surface(Some(&surface));
+            popover.show_all();
+            popover.popup();
+        }
     }
 
     #[tracing::instrument(level = "TRACE")]
-    fn connect_size_allocate(&self, icon_path: Option<PathBuf>) {
+    fn connect_size_allocate(&self) {
+        let icon_path = self.icon_path.clone();
         let last_size = RefCell::new(None);
 
         self.button
@@ -200,6 +372,7 @@ impl Button {
                         - padding.vertical_size();
 
                     // Now we know the size, we can actually load the image.
+                    let icon_path = icon_path.borrow();
                     let image =
                         Self::icon_image(icon_path.as_ref(), button, size).unwrap_or_else(|| {
                             // If we can't find an application icon, then we need to use a
@@ -235,6 +408,7 @@ impl Button {
                     // from the main loop as soon as possible does. :shrug:
                     let button = button.clone();
                     gtk::glib::source::idle_add_local_once(move || {
+                        image.show();
                         button.set_image(Some(&image));
                     });
                 }
@@ -246,18 +420,8 @@ impl Button {
         button: &gtk::Button,
         size: i32,
     ) -> Option<gtk::Image> {
-        let size = size * button.scale_factor();
-
         icon_path
-            .and_then(
-                |path| match Pixbuf::from_file_at_scale(path, size, size, true) {
-                    Ok(pixbuf) => Some(pixbuf),
-                    Err(e) => {
-                        tracing::info!(%e, ?path, "cannot load icon");
-                        None
-                    }
-                },
-            )
+            .and_then(|path| icon::pixbuf(path, size, button.scale_factor()))
             .and_then(|pixbuf| pixbuf.create_surface(0, button.window().as_ref()))
             .map(|surface| gtk::Image::from_surface(Some(&surface)))
     }
@@ -0,0 +1,181 @@
+use std::{path::PathBuf, process::ExitCode};
+
+use futures::StreamExt;
+use tracing_subscriber::EnvFilter;
+use waybar_cffi::gtk::glib::MainContext;
+
+use crate::{
+    config::{Config, OtherOutputs},
+    model::{Attention, ButtonModel, Decorations, Launchers, TaskbarModel},
+    niri::{self, Snapshot},
+    output,
+    state::{Event, State},
+};
+
+static USAGE: &str = "\
+Usage: niri-taskbar-model [--json] [--config PATH] [--output NAME]
+
+Prints the taskbar buttons each time they change, without needing Waybar.
+
+Options:
+  --json           Print each update as a single line of JSON
+  --config PATH    Load the taskbar configuration from a TOML or JSON file
+  --output NAME    Only show windows on the given output, as the taskbar would
+  --help           Show this message
+";
+
+/// Runs the headless taskbar, printing the button model on every change.
+pub fn main() -> ExitCode {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprint!("{e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Standard output is for the model, so logs have to go elsewhere.
+    if let Err(e) = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .try_init()
+    {
+        eprintln!("cannot install global tracing subscriber: {e}");
+    }
+
+    let config = match &args.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Config::default(),
+    };
+
+    let state = State::new(config);
+    MainContext::default().block_on(run(state, args));
+
+    eprintln!("event stream ended");
+    ExitCode::FAILURE
+}
+
+async fn run(state: State, args: Args) {
+    let mut stream = Box::pin(state.event_stream());
+    let mut last_snapshot: Option<Snapshot> = None;
+    let mut filter = build_output_filter(&args, &state.config());
+
+    while let Some(event) = stream.next().await {
+        match event {
+            Event::Niri(niri::Event::Windows(snapshot)) => last_snapshot = Some(snapshot),
+            // The output settings may have changed along with everything else.
+            Event::ConfigReloaded => filter = build_output_filter(&args, &state.config()),
+            _ => continue,
+        }
+
+        if let Some(snapshot) = &last_snapshot {
+            let model = TaskbarModel::new(
+                snapshot,
+                &state.config(),
+                &filter,
+                state.icon_cache(),
+                &Attention::default(),
+                &Decorations::default(),
+                &Launchers::default(),
+            );
+            if args.json {
+                print_json(&model);
+            } else {
+                print_text(&model);
+            }
+        }
+    }
+}
+
+// There's no monitor to match against here, so the output has to be given explicitly if the filter
+// is wanted. Otherwise, every output is considered local.
+fn build_output_filter(args: &Args, config: &Config) -> output::Filter {
+    let local = args
+        .output
+        .clone()
+        .or_else(|| config.output().map(String::from))
+        .or_else(|| std::env::var(output::OUTPUT_ENV).ok());
+
+    output::Filter::new(local, config.other_outputs() != OtherOutputs::Hide)
+}
+
+fn print_json(model: &TaskbarModel) {
+    match serde_json::to_string(model) {
+        Ok(line) => println!("{line}"),
+        Err(e) => tracing::error!(%e, "cannot serialise taskbar model"),
+    }
+}
+
+fn print_text(model: &TaskbarModel) {
+    for button in model.buttons.iter() {
+        print_button(button, "");
+    }
+
+    for group in model.other_outputs.iter() {
+        println!("{}:", group.output);
+        for button in group.buttons.iter() {
+            print_button(button, "  ");
+        }
+    }
+
+    println!();
+}
+
+fn print_button(button: &ButtonModel, indent: &str) {
+    println!(
+        "{indent}{} {} on workspace {}: {}",
+        button.id,
+        button.app_id.as_deref().unwrap_or("(no app ID)"),
+        button.workspace_idx,
+        button.title.as_deref().unwrap_or_default(),
+    );
+    println!("{indent}    classes: {}", button.classes.join(" "));
+    if let Some(label) = &button.label {
+        println!("{indent}    label: {label}");
+    }
+    match &button.icon {
+        Some(icon) => println!("{indent}    icon: {}", icon.display()),
+        None => println!("{indent}    icon: (none)"),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    config: Option<PathBuf>,
+    json: bool,
+    output: Option<String>,
+}
+
+impl Args {
+    /// Parses the command line arguments, returning `None` if help was requested.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut parsed = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    let path = args.next().ok_or("--config requires a path")?;
+                    parsed.config = Some(PathBuf::from(path));
+                }
+                "--help" | "-h" => return Ok(None),
+                "--json" => parsed.json = true,
+                "--output" => {
+                    parsed.output = Some(args.next().ok_or("--output requires an output name")?);
+                }
+                _ => return Err(format!("unexpected argument: {arg}")),
+            }
+        }
+
+        Ok(Some(parsed))
+    }
+}
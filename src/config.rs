@@ -1,18 +1,171 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use itertools::Itertools;
 use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{
+    Deserialize, Deserializer,
+    de::{DeserializeOwned, Error as _, IgnoredAny},
+};
+
+use crate::{error::Error, rules::Rules, tooltip::Template};
+
+/// The raw taskbar configuration, as provided by Waybar.
+///
+/// We parse this into a [`Config`] ourselves, rather than letting waybar-cffi do it, so that
+/// configuration errors can be reported in the bar instead of the module silently vanishing.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct RawConfig(serde_jsonc::Value);
+
+impl RawConfig {
+    /// Parses the raw configuration, warning about any keys that were ignored.
+    ///
+    /// If a configuration file is set, then it is loaded and used instead.
+    pub fn parse(self) -> Result<Config, Error> {
+        let config: Config = serde_jsonc::from_value(self.0).map_err(Error::Config)?;
+        config.warn_unknown_keys();
+
+        match &config.config_file {
+            Some(path) => Config::load(path),
+            None => Ok(config),
+        }
+    }
+}
+
+/// Keys that Waybar handles itself for every CFFI module.
+static WAYBAR_KEYS: &[&str] = &["actions", "module_path"];
+
+/// Unknown keys found while deserialising a configuration object.
+type Unknown = BTreeMap<String, IgnoredAny>;
 
 /// The taskbar configuration.
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
-    #[serde(default)]
+    #[serde(default, deserialize_with = "deserialise_apps")]
     apps: HashMap<String, Vec<AppConfig>>,
     #[serde(default)]
+    attention: Attention,
+    #[serde(default)]
+    config_file: Option<PathBuf>,
+    #[serde(default)]
+    hints: Hints,
+    #[serde(default)]
+    label_other_outputs: bool,
+    #[serde(default)]
     notifications: Notifications,
     #[serde(default)]
+    other_outputs: Option<OtherOutputs>,
+    #[serde(default)]
+    output: Option<String>,
+    #[serde(default)]
+    previews: Previews,
+    #[serde(default)]
+    rules: Rules,
+    #[serde(default)]
     show_all_outputs: bool,
+    #[serde(default)]
+    tooltip: Option<Template>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+/// How windows on outputs other than the one the taskbar is on are shown.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtherOutputs {
+    /// Windows on other outputs aren't shown.
+    #[default]
+    Hide,
+    /// Windows on other outputs are shown alongside local windows.
+    Show,
+    /// Windows on other outputs are shown in a separate group after local windows.
+    Separate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Attention {
+    #[serde(default)]
+    apps: HashMap<String, AppAttention>,
+    #[serde(default = "default_attention_duration")]
+    duration: u64,
+    #[serde(default)]
+    mode: AttentionMode,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+impl Default for Attention {
+    fn default() -> Self {
+        Self {
+            apps: Default::default(),
+            duration: default_attention_duration(),
+            mode: Default::default(),
+            unknown: Default::default(),
+        }
+    }
+}
+
+fn default_attention_duration() -> u64 {
+    10
+}
+
+/// Overrides for the way a single app's windows get attention.
+#[derive(Debug, Deserialize)]
+struct AppAttention {
+    #[serde(default)]
+    duration: Option<u64>,
+    #[serde(default)]
+    mode: Option<AttentionMode>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AttentionMode {
+    #[default]
+    Sticky,
+    Blink,
+    AutoClear,
+}
+
+/// How a window that needs attention is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttentionBehaviour {
+    /// The window is urgent until it's focused.
+    Sticky,
+    /// The window is urgent until it's focused, and blinks for the given duration first.
+    Blink(Duration),
+    /// The window is urgent until it's focused or the given duration has passed.
+    AutoClear(Duration),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Hints {
+    #[serde(
+        default = "default_hint_keys",
+        deserialize_with = "deserialise_hint_keys"
+    )]
+    keys: Vec<char>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+impl Default for Hints {
+    fn default() -> Self {
+        Self {
+            keys: default_hint_keys(),
+            unknown: Default::default(),
+        }
+    }
+}
+
+fn default_hint_keys() -> Vec<char> {
+    "asdfghjkl".chars().collect()
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,6 +178,8 @@ pub struct Notifications {
     use_desktop_entry: bool,
     #[serde(default)]
     use_fuzzy_matching: bool,
+    #[serde(flatten)]
+    unknown: Unknown,
 }
 
 impl Default for Notifications {
@@ -34,26 +189,79 @@ impl Default for Notifications {
             map_app_ids: Default::default(),
             use_desktop_entry: true,
             use_fuzzy_matching: Default::default(),
+            unknown: Default::default(),
         }
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct Previews {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_preview_refresh")]
+    refresh: u64,
+    #[serde(default = "default_preview_size")]
+    size: i32,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+impl Default for Previews {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            refresh: default_preview_refresh(),
+            size: default_preview_size(),
+            unknown: Default::default(),
+        }
+    }
+}
+
+fn default_preview_refresh() -> u64 {
+    30
+}
+
+fn default_preview_size() -> i32 {
+    240
+}
+
 fn default_true() -> bool {
     true
 }
 
 impl Config {
-    /// Returns all possible CSS classes that a particular application might have set.
-    pub fn app_classes(&self, app_id: &str) -> Vec<&str> {
-        self.apps
-            .get(app_id)
-            .map(|configs| {
-                configs
-                    .iter()
-                    .map(|config| config.class.as_str())
-                    .collect_vec()
-            })
-            .unwrap_or_default()
+    /// Loads the configuration from a file.
+    ///
+    /// Files with a `.toml` extension are parsed as TOML; anything else is parsed as JSON (with
+    /// comments), the same as the Waybar configuration. The returned configuration always refers
+    /// back to the file it was loaded from.
+    #[tracing::instrument(level = "DEBUG", err)]
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path).map_err(|e| Error::ConfigFile {
+            e,
+            path: path.to_path_buf(),
+        })?;
+
+        let mut config: Self = if path.extension().is_some_and(|ext| ext == "toml") {
+            toml::from_str(&content).map_err(|e| Error::ConfigToml {
+                e,
+                path: path.to_path_buf(),
+            })?
+        } else {
+            serde_jsonc::from_str(&content).map_err(|e| Error::ConfigJson {
+                e,
+                path: path.to_path_buf(),
+            })?
+        };
+        config.warn_unknown_keys();
+        config.config_file = Some(path.to_path_buf());
+
+        Ok(config)
+    }
+
+    /// Returns the path to the configuration file, if there is one.
+    pub fn config_file(&self) -> Option<&Path> {
+        self.config_file.as_deref()
     }
 
     /// Returns the actual CSS classes that should be set for the given application and title.
@@ -73,6 +281,33 @@ impl Config {
         }
     }
 
+    /// Returns how windows with the given app ID are shown when they need attention.
+    pub fn attention(&self, app_id: Option<&str>) -> AttentionBehaviour {
+        let app = app_id.and_then(|app_id| self.attention.apps.get(app_id));
+        let mode = app.and_then(|app| app.mode).unwrap_or(self.attention.mode);
+        let duration = Duration::from_secs(
+            app.and_then(|app| app.duration)
+                .unwrap_or(self.attention.duration),
+        );
+
+        match mode {
+            AttentionMode::Sticky => AttentionBehaviour::Sticky,
+            AttentionMode::Blink => AttentionBehaviour::Blink(duration),
+            AttentionMode::AutoClear => AttentionBehaviour::AutoClear(duration),
+        }
+    }
+
+    /// Returns the keys used to build window hints, in order of preference.
+    pub fn hint_keys(&self) -> &[char] {
+        &self.hints.keys
+    }
+
+    /// Returns true if the separate group of windows on other outputs should be labelled with the
+    /// output names.
+    pub fn label_other_outputs(&self) -> bool {
+        self.label_other_outputs
+    }
+
     /// Returns true if notification support is enabled.
     pub fn notifications_enabled(&self) -> bool {
         self.notifications.enabled
@@ -96,8 +331,106 @@ impl Config {
         self.notifications.use_fuzzy_matching
     }
 
-    pub fn show_all_outputs(&self) -> bool {
-        self.show_all_outputs
+    /// Returns the name of the output the taskbar is on, if explicitly configured.
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// Returns true if window previews should be shown on hover.
+    pub fn previews_enabled(&self) -> bool {
+        self.previews.enabled
+    }
+
+    /// Returns the minimum time between captures of the same window's preview.
+    pub fn previews_refresh(&self) -> Duration {
+        Duration::from_secs(self.previews.refresh)
+    }
+
+    /// Returns the maximum width and height of window previews, in logical pixels.
+    pub fn previews_size(&self) -> i32 {
+        self.previews.size
+    }
+
+    /// Returns the window rules.
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Returns how windows on other outputs should be shown.
+    ///
+    /// `show_all_outputs` predates `other_outputs`, and is equivalent to setting it to `show`.
+    pub fn other_outputs(&self) -> OtherOutputs {
+        match self.other_outputs {
+            Some(mode) => mode,
+            None if self.show_all_outputs => OtherOutputs::Show,
+            None => OtherOutputs::Hide,
+        }
+    }
+
+    /// Returns the tooltip template, if one is configured.
+    pub fn tooltip(&self) -> Option<&Template> {
+        self.tooltip.as_ref()
+    }
+
+    fn warn_unknown_keys(&self) {
+        for key in self.unknown_keys() {
+            tracing::warn!(key, "unknown configuration key; ignoring");
+        }
+    }
+
+    /// Returns the paths of any keys that were present in the configuration but not recognised.
+    fn unknown_keys(&self) -> Vec<String> {
+        let mut keys = self
+            .unknown
+            .keys()
+            .filter(|key| !WAYBAR_KEYS.contains(&key.as_str()) && !key.starts_with("on-"))
+            .cloned()
+            .collect_vec();
+
+        keys.extend(
+            self.attention
+                .unknown
+                .keys()
+                .map(|key| format!("attention.{key}")),
+        );
+        for (app_id, app) in self
+            .attention
+            .apps
+            .iter()
+            .sorted_by_key(|(app_id, _)| *app_id)
+        {
+            keys.extend(
+                app.unknown
+                    .keys()
+                    .map(|key| format!("attention.apps.{app_id}.{key}")),
+            );
+        }
+        keys.extend(self.hints.unknown.keys().map(|key| format!("hints.{key}")));
+        keys.extend(
+            self.notifications
+                .unknown
+                .keys()
+                .map(|key| format!("notifications.{key}")),
+        );
+        keys.extend(
+            self.previews
+                .unknown
+                .keys()
+                .map(|key| format!("previews.{key}")),
+        );
+        for (app_id, configs) in self.apps.iter().sorted_by_key(|(app_id, _)| *app_id) {
+            for (i, config) in configs.iter().enumerate() {
+                keys.extend(
+                    config
+                        .unknown
+                        .keys()
+                        .map(|key| format!("apps.{app_id}[{i}].{key}")),
+                );
+            }
+        }
+        keys.extend(self.rules.unknown_keys());
+
+        keys
     }
 }
 
@@ -106,6 +439,56 @@ struct AppConfig {
     #[serde(rename = "match", deserialize_with = "deserialise_regex")]
     re: Regex,
     class: String,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+fn deserialise_apps<'de, D>(de: D) -> Result<HashMap<String, Vec<AppConfig>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    HashMap::<String, Vec<serde_jsonc::Value>>::deserialize(de)?
+        .into_iter()
+        .map(|(app_id, values)| {
+            let configs = deserialise_each(values, |i| format!("apps.{app_id}[{i}]"))?;
+            Ok((app_id, configs))
+        })
+        .try_collect()
+}
+
+/// Deserialises each value in a list, prefixing any error with the path of the value at fault.
+///
+/// Deserialising the whole list in one go would only report the position within the file, which
+/// isn't much help when the configuration came from Waybar and has been parsed already.
+pub(crate) fn deserialise_each<T, E>(
+    values: Vec<serde_jsonc::Value>,
+    path: impl Fn(usize) -> String,
+) -> Result<Vec<T>, E>
+where
+    T: DeserializeOwned,
+    E: serde::de::Error,
+{
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| T::deserialize(value).map_err(|e| E::custom(format!("{}: {e}", path(i)))))
+        .try_collect()
+}
+
+// Hints are built from combinations of keys, so they need at least two distinct keys to be able to
+// tell windows apart.
+fn deserialise_hint_keys<'de, D>(de: D) -> Result<Vec<char>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let keys = String::deserialize(de)?.chars().unique().collect_vec();
+    if keys.len() < 2 {
+        return Err(D::Error::custom(
+            "hints.keys must contain at least two different characters",
+        ));
+    }
+
+    Ok(keys)
 }
 
 fn deserialise_regex<'de, D>(de: D) -> Result<Regex, D::Error>
@@ -114,3 +497,142 @@ where
 {
     Regex::new(&String::deserialize(de)?).map_err(serde::de::Error::custom)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Config {
+        serde_jsonc::from_str(json).unwrap()
+    }
+
+    fn error(json: &str) -> String {
+        serde_jsonc::from_str::<Config>(json)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn load_parses_toml_or_jsonc_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let toml = dir.path().join("taskbar.toml");
+        std::fs::write(&toml, "output = \"DP-1\"\n\n[hints]\nkeys = \"jk\"\n").unwrap();
+        let config = Config::load(&toml).unwrap();
+        assert_eq!(config.output(), Some("DP-1"));
+        assert_eq!(config.hint_keys(), ['j', 'k']);
+        assert_eq!(config.config_file(), Some(toml.as_path()));
+
+        // Anything that isn't TOML is JSON with comments, whatever the extension.
+        let jsonc = dir.path().join("taskbar.conf");
+        std::fs::write(
+            &jsonc,
+            "{\n  // The laptop screen.\n  \"output\": \"eDP-1\"\n}\n",
+        )
+        .unwrap();
+        let config = Config::load(&jsonc).unwrap();
+        assert_eq!(config.output(), Some("eDP-1"));
+        assert_eq!(config.config_file(), Some(jsonc.as_path()));
+
+        let json_in_toml = dir.path().join("json.toml");
+        std::fs::write(&json_in_toml, r#"{ "output": "DP-1" }"#).unwrap();
+        assert!(matches!(
+            Config::load(&json_in_toml),
+            Err(Error::ConfigToml { .. })
+        ));
+        assert!(matches!(
+            Config::load(&dir.path().join("missing.json")),
+            Err(Error::ConfigFile { .. })
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_paths() {
+        let config = config(
+            r#"{
+                "show_all_output": true,
+                "attention": {
+                    "colour": "red",
+                    "apps": { "foot": { "mode": "blink", "speed": 2 } }
+                },
+                "hints": { "key": "ab" },
+                "notifications": { "enable": false },
+                "previews": { "width": 100 },
+                "apps": {
+                    "firefox": [
+                        { "match": "Mail", "class": "mail" },
+                        { "match": "Chat", "class": "chat", "colour": "blue" }
+                    ]
+                },
+                "rules": [{ "app_id": "foot", "clas": "terminal" }]
+            }"#,
+        );
+
+        assert_eq!(
+            config.unknown_keys(),
+            vec![
+                "show_all_output",
+                "attention.colour",
+                "attention.apps.foot.speed",
+                "hints.key",
+                "notifications.enable",
+                "previews.width",
+                "apps.firefox[1].colour",
+                "rules[0].clas",
+            ]
+        );
+    }
+
+    #[test]
+    fn waybar_keys_are_not_reported() {
+        let config = config(
+            r#"{
+                "module_path": "/usr/lib/libniri_taskbar.so",
+                "actions": {},
+                "on-click": "activate",
+                "on-scroll-up": "next",
+                "show_all_outputs": true
+            }"#,
+        );
+
+        assert_eq!(config.unknown_keys(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn app_regex_errors_name_the_app_config() {
+        let e = error(
+            r#"{ "apps": { "firefox": [{ "match": "Mail", "class": "mail" }, { "match": "(", "class": "x" }] } }"#,
+        );
+        assert!(e.contains("apps.firefox[1]"), "{e}");
+    }
+
+    #[test]
+    fn hint_keys_need_two_different_characters() {
+        assert_eq!(
+            config(r#"{ "hints": { "keys": "abab" } }"#).hint_keys(),
+            ['a', 'b']
+        );
+        assert_eq!(config("{}").hint_keys().len(), 9);
+
+        let e = error(r#"{ "hints": { "keys": "aaa" } }"#);
+        assert!(e.contains("at least two different characters"), "{e}");
+        assert!(serde_jsonc::from_str::<Config>(r#"{ "hints": { "keys": "" } }"#).is_err());
+    }
+
+    #[test]
+    fn show_all_outputs_is_the_same_as_showing_other_outputs() {
+        assert_eq!(config("{}").other_outputs(), OtherOutputs::Hide);
+        assert_eq!(
+            config(r#"{ "show_all_outputs": true }"#).other_outputs(),
+            OtherOutputs::Show
+        );
+        assert_eq!(
+            config(r#"{ "show_all_outputs": true, "other_outputs": "separate" }"#).other_outputs(),
+            OtherOutputs::Separate
+        );
+        assert_eq!(
+            config(r#"{ "show_all_outputs": true, "other_outputs": "hide" }"#).other_outputs(),
+            OtherOutputs::Hide
+        );
+    }
+}
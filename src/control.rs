@@ -0,0 +1,582 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, LazyLock, Mutex, MutexGuard, Once, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use async_channel::{Receiver, Sender};
+use serde::Serialize;
+use waybar_cffi::gtk::glib;
+use zbus::{Connection, fdo, interface, object_server::SignalEmitter, zvariant::Type};
+
+use crate::model::Decoration;
+
+static NAME: &str = "org.niri.Taskbar";
+static PATH: &str = "/org/niri/Taskbar";
+
+/// A command received over D-Bus.
+#[derive(Debug, Clone)]
+pub enum Command {
+    /// Changes the decorations on the matching windows, with the change expiring after the
+    /// timeout, if any.
+    Decorate {
+        target: Target,
+        decoration: Decoration,
+        timeout: Option<Duration>,
+    },
+    /// Focuses the given window.
+    Focus(u64),
+    /// Shows window hints.
+    Hint,
+    /// Reloads the configuration file.
+    ReloadConfig,
+    /// Marks the matching windows as urgent, or clears their urgency.
+    SetUrgent { target: Target, urgent: bool },
+}
+
+/// The windows that a command applies to.
+///
+/// Targets are resolved to the windows that match when the command arrives, and the command then
+/// applies to those windows only. In particular, a process ID target doesn't pick up windows the
+/// process (or its parents) opens afterwards.
+#[derive(Debug, Clone)]
+pub enum Target {
+    App(String),
+    /// Windows belonging to the process, or any of its ancestors.
+    Pid(i64),
+    Window(u64),
+}
+
+impl Target {
+    /// Parses a target given as `id:<window ID>`, `pid:<process ID>`, or `app_id:<app ID>`.
+    fn parse(target: &str) -> fdo::Result<Self> {
+        let invalid = || {
+            fdo::Error::InvalidArgs(format!(
+                "invalid target {target:?}; expected id:<window ID>, pid:<process ID>, or app_id:<app ID>"
+            ))
+        };
+
+        match target.split_once(':').ok_or_else(invalid)? {
+            ("id", id) => id.parse().map(Self::Window).map_err(|_| invalid()),
+            ("pid", pid) => pid.parse().map(Self::Pid).map_err(|_| invalid()),
+            ("app_id", app_id) if !app_id.is_empty() => Ok(Self::App(app_id.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// What a taskbar is currently showing, as far as the D-Bus interface is concerned.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Published {
+    /// The output the taskbar is on, if known.
+    pub output: Option<String>,
+    /// True if the taskbar is on the focused output.
+    pub focused: bool,
+    /// The buttons on the taskbar, in order.
+    pub buttons: Vec<Button>,
+}
+
+/// A button, as returned by the `List` method.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Type)]
+pub struct Button {
+    pub id: u64,
+    pub app_id: String,
+    pub title: String,
+    pub urgent: bool,
+}
+
+/// A taskbar's registration with the D-Bus interface, which is removed when dropped.
+///
+/// Waybar creates a taskbar for each bar within the same process, but the D-Bus name can only be
+/// owned once, so the interface is served when the first taskbar registers, and commands are
+/// routed to the taskbar they apply to based on what each taskbar has published.
+#[derive(Debug)]
+pub struct Control {
+    id: usize,
+}
+
+impl Control {
+    /// Registers a new taskbar, returning a channel that receives the commands sent to it.
+    pub fn register() -> (Self, Receiver<Command>) {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        static SERVE: Once = Once::new();
+
+        SERVE.call_once(|| {
+            glib::spawn_future_local(async {
+                if let Err(e) = serve().await {
+                    tracing::error!(%e, "cannot serve the taskbar D-Bus interface");
+                }
+            });
+        });
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = async_channel::unbounded();
+        Registry::global().lock().insert(
+            id,
+            Taskbar {
+                commands: tx,
+                published: Default::default(),
+            },
+        );
+
+        (Self { id }, rx)
+    }
+
+    /// Updates what the taskbar is showing, emitting `WindowsChanged` if the visible windows have
+    /// changed.
+    pub fn publish(&self, published: Published) {
+        let mut taskbars = Registry::global().lock();
+        let Some(taskbar) = taskbars.get_mut(&self.id) else {
+            return;
+        };
+
+        let old = std::mem::replace(&mut taskbar.published, published);
+        let new = &taskbar.published;
+        let ids = |published: &Published| {
+            published
+                .buttons
+                .iter()
+                .map(|button| button.id)
+                .collect::<Vec<_>>()
+        };
+        let windows = ids(new);
+        if windows == ids(&old) {
+            return;
+        }
+
+        if let Some(connection) = CONNECTION.get() {
+            let connection = connection.clone();
+            let output = new.output.clone().unwrap_or_default();
+            glib::spawn_future_local(async move {
+                if let Err(e) = emit_windows_changed(&connection, &output, &windows).await {
+                    tracing::warn!(%e, "cannot emit WindowsChanged signal");
+                }
+            });
+        }
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        Registry::global().lock().remove(&self.id);
+    }
+}
+
+struct Taskbar {
+    commands: Sender<Command>,
+    published: Published,
+}
+
+impl Taskbar {
+    fn send(&self, command: Command) {
+        if let Err(e) = self.commands.try_send(command) {
+            tracing::warn!(%e, "cannot send command to taskbar");
+        }
+    }
+}
+
+/// The taskbars that commands can be routed to, keyed by registration ID.
+#[derive(Default)]
+struct Registry(Mutex<BTreeMap<usize, Taskbar>>);
+
+impl Registry {
+    /// Returns the registry of the taskbars in this process.
+    fn global() -> &'static Arc<Self> {
+        static REGISTRY: LazyLock<Arc<Registry>> = LazyLock::new(Default::default);
+
+        &REGISTRY
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<usize, Taskbar>> {
+        self.0.lock().expect("taskbar list lock")
+    }
+
+    // Finds the taskbar on the given output, or the focused output if the output is empty, and
+    // calls the given function with it.
+    fn with_taskbar<T>(
+        &self,
+        output: &str,
+        f: impl FnOnce(&Taskbar) -> fdo::Result<T>,
+    ) -> fdo::Result<T> {
+        match find_taskbar(&self.lock(), output) {
+            Some(taskbar) => f(taskbar),
+            None if output.is_empty() => Err(fdo::Error::Failed("no taskbar is running".into())),
+            None => Err(fdo::Error::InvalidArgs(format!(
+                "no taskbar is on output {output}"
+            ))),
+        }
+    }
+
+    fn broadcast(&self, command: Command) {
+        for taskbar in self.lock().values() {
+            taskbar.send(command.clone());
+        }
+    }
+
+    // Timeouts are given in seconds over D-Bus, with zero meaning that there's no timeout.
+    fn decorate(&self, target: &str, decoration: Decoration, timeout: u32) -> fdo::Result<()> {
+        self.broadcast(Command::Decorate {
+            target: Target::parse(target)?,
+            decoration,
+            timeout: (timeout > 0).then(|| Duration::from_secs(timeout.into())),
+        });
+        Ok(())
+    }
+}
+
+/// The connection the interface is served on, once it's up.
+static CONNECTION: OnceLock<Connection> = OnceLock::new();
+
+#[tracing::instrument(level = "DEBUG", err)]
+async fn serve() -> zbus::Result<()> {
+    let connection = serve_on(
+        zbus::connection::Builder::session()?,
+        Registry::global().clone(),
+    )
+    .await?;
+    tracing::info!(name = NAME, path = PATH, "serving taskbar D-Bus interface");
+
+    // The connection is kept for the lifetime of the process, since the interface is only served
+    // while it's alive.
+    let _ = CONNECTION.set(connection);
+    Ok(())
+}
+
+// Serves the interface for the given taskbars on the connection being built.
+async fn serve_on(
+    builder: zbus::connection::Builder<'_>,
+    registry: Arc<Registry>,
+) -> zbus::Result<Connection> {
+    builder
+        .name(NAME)?
+        .serve_at(PATH, Interface { registry })?
+        .build()
+        .await
+}
+
+async fn emit_windows_changed(
+    connection: &Connection,
+    output: &str,
+    windows: &[u64],
+) -> zbus::Result<()> {
+    Interface::windows_changed(&SignalEmitter::new(connection, PATH)?, output, windows).await
+}
+
+fn find_taskbar<'a>(taskbars: &'a BTreeMap<usize, Taskbar>, output: &str) -> Option<&'a Taskbar> {
+    if output.is_empty() {
+        return taskbars
+            .values()
+            .find(|taskbar| taskbar.published.focused)
+            .or_else(|| taskbars.values().next());
+    }
+
+    // If a taskbar doesn't know which output it's on, then it shows windows on every output, so
+    // it's as good a match as any, but only if no taskbar is actually on the output.
+    taskbars
+        .values()
+        .find(|taskbar| taskbar.published.output.as_deref() == Some(output))
+        .or_else(|| {
+            taskbars
+                .values()
+                .find(|taskbar| taskbar.published.output.is_none())
+        })
+}
+
+struct Interface {
+    registry: Arc<Registry>,
+}
+
+#[interface(name = "org.niri.Taskbar")]
+impl Interface {
+    /// Adds a CSS class to the windows that currently match the target until the timeout (in
+    /// seconds) expires, or forever if it's zero.
+    fn add_class(&self, target: &str, class: &str, timeout: u32) -> fdo::Result<()> {
+        if class.is_empty() {
+            return Err(fdo::Error::InvalidArgs("the class cannot be empty".into()));
+        }
+
+        self.registry
+            .decorate(target, Decoration::Class(class.to_string()), timeout)
+    }
+
+    /// Removes every class, badge, and progress fraction set on the windows that currently match
+    /// the target.
+    fn clear_decorations(&self, target: &str) -> fdo::Result<()> {
+        self.registry.decorate(target, Decoration::Clear, 0)
+    }
+
+    /// Focuses the window on the given button (counting from 1) of the taskbar on the given
+    /// output, or the focused output if the output is empty.
+    fn focus(&self, button: u32, output: &str) -> fdo::Result<()> {
+        self.registry.with_taskbar(output, |taskbar| {
+            let id = button
+                .checked_sub(1)
+                .and_then(|i| taskbar.published.buttons.get(i as usize))
+                .map(|found| found.id)
+                .ok_or_else(|| fdo::Error::InvalidArgs(format!("no button {button}")))?;
+
+            taskbar.send(Command::Focus(id));
+            Ok(())
+        })
+    }
+
+    /// Shows a hint on each button of the taskbar on the given output, or the focused output if
+    /// the output is empty. The keys typed next pick the window to focus.
+    fn hint(&self, output: &str) -> fdo::Result<()> {
+        self.registry.with_taskbar(output, |taskbar| {
+            taskbar.send(Command::Hint);
+            Ok(())
+        })
+    }
+
+    /// Lists the buttons on the taskbar on the given output, or the focused output if the output
+    /// is empty, in order.
+    fn list(&self, output: &str) -> fdo::Result<Vec<Button>> {
+        self.registry
+            .with_taskbar(output, |taskbar| Ok(taskbar.published.buttons.clone()))
+    }
+
+    /// Reloads the configuration file, if the configuration was loaded from one.
+    fn reload_config(&self) {
+        self.registry.broadcast(Command::ReloadConfig);
+    }
+
+    /// Sets the badge text on the windows that currently match the target until the timeout (in
+    /// seconds) expires, or forever if it's zero. An empty badge removes it.
+    fn set_badge(&self, target: &str, badge: &str, timeout: u32) -> fdo::Result<()> {
+        let badge = (!badge.is_empty()).then(|| badge.to_string());
+        self.registry
+            .decorate(target, Decoration::Badge(badge), timeout)
+    }
+
+    /// Sets the progress fraction on the windows that currently match the target until the timeout
+    /// (in seconds) expires, or forever if it's zero. Fractions are clamped between 0 and 1, and a
+    /// negative fraction removes it.
+    fn set_progress(&self, target: &str, progress: f64, timeout: u32) -> fdo::Result<()> {
+        let progress = (progress >= 0.0).then_some(progress);
+        self.registry
+            .decorate(target, Decoration::Progress(progress), timeout)
+    }
+
+    /// Marks every window with the given app ID as urgent, or clears their urgency.
+    fn set_app_urgent(&self, app_id: &str, urgent: bool) {
+        self.registry.broadcast(Command::SetUrgent {
+            target: Target::App(app_id.to_string()),
+            urgent,
+        });
+    }
+
+    /// Marks the window with the given ID as urgent, or clears its urgency.
+    fn set_urgent(&self, window: u64, urgent: bool) {
+        self.registry.broadcast(Command::SetUrgent {
+            target: Target::Window(window),
+            urgent,
+        });
+    }
+
+    /// Emitted when the windows shown on a taskbar change, with the output the taskbar is on (or
+    /// an empty string if it isn't known) and the window IDs in order.
+    #[zbus(signal)]
+    async fn windows_changed(
+        emitter: &SignalEmitter<'_>,
+        output: &str,
+        windows: &[u64],
+    ) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command as Process, Stdio},
+    };
+
+    use super::*;
+
+    fn taskbar(output: Option<&str>, focused: bool, ids: &[u64]) -> (Taskbar, Receiver<Command>) {
+        let (tx, rx) = async_channel::unbounded();
+        let taskbar = Taskbar {
+            commands: tx,
+            published: Published {
+                output: output.map(String::from),
+                focused,
+                buttons: ids
+                    .iter()
+                    .map(|id| Button {
+                        id: *id,
+                        app_id: format!("app{id}"),
+                        title: format!("Window {id}"),
+                        urgent: false,
+                    })
+                    .collect(),
+            },
+        };
+        (taskbar, rx)
+    }
+
+    // A taskbar that doesn't know its output comes before the taskbars that do, so that matching
+    // on the first taskbar that could be on an output would pick the wrong one.
+    fn taskbars() -> (BTreeMap<usize, Taskbar>, Vec<Receiver<Command>>) {
+        [
+            taskbar(None, false, &[1]),
+            taskbar(Some("DP-1"), false, &[2, 3]),
+            taskbar(Some("HDMI-A-1"), true, &[4]),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (taskbar, rx))| ((i, taskbar), rx))
+        .unzip()
+    }
+
+    fn found(taskbars: &BTreeMap<usize, Taskbar>, output: &str) -> Option<Vec<u64>> {
+        find_taskbar(taskbars, output).map(|taskbar| {
+            taskbar
+                .published
+                .buttons
+                .iter()
+                .map(|button| button.id)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn exact_output_match_wins() {
+        let (taskbars, _) = taskbars();
+
+        assert_eq!(found(&taskbars, "DP-1"), Some(vec![2, 3]));
+        assert_eq!(found(&taskbars, "HDMI-A-1"), Some(vec![4]));
+    }
+
+    #[test]
+    fn unknown_output_is_the_fallback() {
+        let (mut taskbars, _) = taskbars();
+
+        assert_eq!(found(&taskbars, "DP-2"), Some(vec![1]));
+
+        taskbars.remove(&0);
+        assert_eq!(found(&taskbars, "DP-2"), None);
+    }
+
+    #[test]
+    fn empty_output_is_the_focused_taskbar() {
+        let (mut taskbars, _) = taskbars();
+
+        assert_eq!(found(&taskbars, ""), Some(vec![4]));
+
+        taskbars.remove(&2);
+        assert_eq!(found(&taskbars, ""), Some(vec![1]));
+
+        taskbars.clear();
+        assert_eq!(found(&taskbars, ""), None);
+    }
+
+    #[test]
+    fn targets() {
+        assert!(matches!(Target::parse("id:42"), Ok(Target::Window(42))));
+        assert!(matches!(Target::parse("pid:1234"), Ok(Target::Pid(1234))));
+        assert!(matches!(
+            Target::parse("app_id:org.example.App"),
+            Ok(Target::App(app_id)) if app_id == "org.example.App"
+        ));
+
+        for invalid in ["", "42", "id:", "id:x", "pid:-", "app_id:", "window:42"] {
+            assert!(Target::parse(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    /// A private session bus, which is shut down when dropped.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        /// Starts a bus, if `dbus-daemon` is installed.
+        fn start() -> Option<Self> {
+            let mut daemon = Process::new("dbus-daemon")
+                .args(["--session", "--print-address", "--nofork"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut address)
+                .ok()?;
+
+            Some(Self {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        fn connect(&self) -> zbus::Result<zbus::connection::Builder<'static>> {
+            zbus::connection::Builder::address(self.address.as_str())
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    type ListedButton = (u64, String, String, bool);
+
+    #[test]
+    fn interface_over_dbus() {
+        let Some(bus) = Bus::start() else {
+            eprintln!("dbus-daemon is not available; skipping");
+            return;
+        };
+
+        let (registered, receivers) = taskbars();
+        let registry = Arc::new(Registry(Mutex::new(registered)));
+
+        glib::MainContext::new().block_on(async {
+            let _server = serve_on(bus.connect().unwrap(), registry).await.unwrap();
+            let client = bus.connect().unwrap().build().await.unwrap();
+            let proxy = zbus::Proxy::new(&client, NAME, PATH, NAME).await.unwrap();
+
+            let listed: Vec<ListedButton> = proxy.call("List", &("HDMI-A-1",)).await.unwrap();
+            assert_eq!(
+                listed,
+                vec![(4, "app4".to_string(), "Window 4".to_string(), false)]
+            );
+            let listed: Vec<ListedButton> = proxy.call("List", &("DP-1",)).await.unwrap();
+            assert_eq!(
+                listed.iter().map(|button| button.0).collect::<Vec<_>>(),
+                vec![2, 3]
+            );
+
+            let () = proxy.call("Focus", &(2u32, "DP-1")).await.unwrap();
+            assert!(matches!(receivers[1].try_recv(), Ok(Command::Focus(3))));
+
+            let e = proxy
+                .call::<_, _, ()>("Focus", &(3u32, "DP-1"))
+                .await
+                .unwrap_err();
+            let name = match &e {
+                zbus::Error::MethodError(name, _, _) => name.as_str(),
+                _ => panic!("unexpected error {e:?}"),
+            };
+            assert_eq!(name, "org.freedesktop.DBus.Error.InvalidArgs");
+
+            let () = proxy.call("SetUrgent", &(7u64, true)).await.unwrap();
+            for rx in receivers.iter() {
+                assert!(matches!(
+                    rx.try_recv(),
+                    Ok(Command::SetUrgent {
+                        target: Target::Window(7),
+                        urgent: true
+                    })
+                ));
+            }
+        });
+    }
+}
@@ -1,21 +1,77 @@
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("niri IPC: {0}")]
-    NiriIpc(#[source] std::io::Error),
+    #[error("configuration: {0}")]
+    Config(#[source] serde_jsonc::Error),
+
+    #[error("cannot read configuration file {path:?}: {e}")]
+    ConfigFile {
+        #[source]
+        e: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("configuration file {path:?}: {e}")]
+    ConfigJson {
+        #[source]
+        e: serde_jsonc::Error,
+        path: PathBuf,
+    },
+
+    #[error("configuration file {path:?}: {e}")]
+    ConfigToml {
+        #[source]
+        e: toml::de::Error,
+        path: PathBuf,
+    },
+
+    #[error("event stream send error")]
+    EventStreamSend,
+
+    #[error("niri closed the connection")]
+    NiriDisconnected,
+
+    #[error("niri IPC message: {0}")]
+    NiriJson(#[source] serde_json::Error),
 
     #[error("niri reply: {0}")]
     NiriReply(String),
 
+    #[error("niri IPC: {0}")]
+    NiriSocket(#[source] waybar_cffi::gtk::glib::Error),
+
+    #[error("NIRI_SOCKET is not set; is this running within niri?")]
+    NiriSocketPath,
+
+    #[error("window preview: {0}")]
+    Preview(#[source] std::io::Error),
+
+    #[error("timed out waiting for window preview")]
+    PreviewTimeout,
+
+    #[error("cannot read recording {path:?}: {e}")]
+    Recording {
+        #[source]
+        e: std::io::Error,
+        path: PathBuf,
+    },
+
+    #[error("recording {path:?}, line {line}: {e}")]
+    RecordingEntry {
+        #[source]
+        e: serde_json::Error,
+        line: usize,
+        path: PathBuf,
+    },
+
     #[error("unexpected niri response; expected {name}: {response:?}")]
     UnexpectedResponse {
         name: &'static str,
         response: Box<niri_ipc::Response>,
     },
-
-    #[error("window stream send error")]
-    WindowStreamSend,
 }
 
 impl Error {
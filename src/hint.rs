@@ -0,0 +1,82 @@
+use async_channel::{Receiver, Sender};
+use waybar_cffi::gtk::{
+    self as gtk,
+    gdk::{Gravity, keys::constants as keys},
+    glib::{self, IsA},
+    prelude::{GtkMenuExt, MenuShellExt, StyleContextExt, WidgetExt},
+};
+
+/// A key typed while hints are shown.
+#[derive(Debug, Clone, Copy)]
+pub enum Key {
+    Char(char),
+    Backspace,
+    Cancel,
+}
+
+/// The popup that grabs the keyboard while hints are shown.
+///
+/// The bar itself never gets keyboard focus, but Gtk menus grab the keyboard when they pop up,
+/// including from a layer shell surface like Waybar's. So we pop up a small menu, and take over
+/// its key handling.
+pub struct Popup {
+    menu: gtk::Menu,
+}
+
+impl Popup {
+    /// Creates a new popup attached to the given widget, along with a channel that receives the
+    /// keys typed while it's shown.
+    pub fn new(attach_to: &impl IsA<gtk::Widget>) -> (Self, Receiver<Key>) {
+        let (tx, rx) = async_channel::unbounded();
+
+        let menu = gtk::Menu::builder().attach_widget(attach_to).build();
+        menu.style_context().add_class("niri-taskbar-hint");
+
+        let item = gtk::MenuItem::with_label("Type a hint to focus its window");
+        item.set_sensitive(false);
+        menu.append(&item);
+        item.show();
+
+        menu.connect_key_press_event({
+            let tx = tx.clone();
+            move |_, event| {
+                let keyval = event.keyval();
+                let key = if keyval == keys::BackSpace {
+                    Key::Backspace
+                } else if let Some(c) = keyval.to_unicode().filter(|c| !c.is_control()) {
+                    Key::Char(c)
+                } else {
+                    // Anything else (including Escape, which closes the menu) is left to the menu.
+                    return glib::Propagation::Proceed;
+                };
+
+                send(&tx, key);
+                glib::Propagation::Stop
+            }
+        });
+
+        // However the menu was closed, the hints need to go away.
+        menu.connect_deactivate(move |_| send(&tx, Key::Cancel));
+
+        (Self { menu }, rx)
+    }
+
+    /// Shows the popup below the widget it's attached to.
+    pub fn show(&self) {
+        if let Some(widget) = self.menu.attach_widget() {
+            self.menu
+                .popup_at_widget(&widget, Gravity::SouthWest, Gravity::NorthWest, None);
+        }
+    }
+
+    /// Hides the popup.
+    pub fn hide(&self) {
+        self.menu.popdown();
+    }
+}
+
+fn send(tx: &Sender<Key>, key: Key) {
+    if let Err(e) = tx.try_send(key) {
+        tracing::error!(%e, "error sending hint key");
+    }
+}
@@ -1,12 +1,15 @@
 use std::{
+    cell::RefCell,
     collections::HashMap,
-    path::PathBuf,
-    sync::{Arc, LazyLock, Mutex},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, Once},
 };
 
 use waybar_cffi::gtk::{
+    IconTheme,
+    gdk_pixbuf::Pixbuf,
     gio::DesktopAppInfo,
-    prelude::{AppInfoExt, IconExt},
+    prelude::{AppInfoExt, IconExt, IconThemeExt},
 };
 
 /// A cache for taskbar icons.
@@ -27,6 +30,123 @@ impl Cache {
 
         cache.get(id).cloned()
     }
+
+    /// Forgets every icon that has been looked up.
+    pub fn clear(&self) {
+        self.0.lock().expect("icon cache lock").clear();
+    }
+}
+
+/// Clears the given icon cache and the decoded pixbuf cache whenever the default icon theme
+/// changes, since the same app ID can then resolve to a different file.
+///
+/// This only needs to be called once per process, so later calls do nothing.
+pub fn clear_on_theme_change(cache: &Cache) {
+    static CONNECTED: Once = Once::new();
+
+    CONNECTED.call_once(|| {
+        let Some(theme) = IconTheme::default() else {
+            return;
+        };
+
+        let cache = cache.clone();
+        theme.connect_changed(move |_| {
+            tracing::debug!("icon theme changed; clearing icon caches");
+            cache.clear();
+            clear_pixbufs();
+        });
+    });
+}
+
+/// The most decoded pixbufs that will be kept around.
+///
+/// Each distinct icon, size, and scale factor gets its own entry, so this leaves plenty of room
+/// for a busy taskbar on a couple of outputs with different scales, while stopping the cache from
+/// growing forever as buttons are resized.
+const PIXBUF_CACHE_CAPACITY: usize = 128;
+
+// Pixbufs aren't Send, so the decoded icon cache has to live on the main thread alongside the
+// buttons that use it.
+thread_local! {
+    static PIXBUF_CACHE: RefCell<PixbufCache> = Default::default();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PixbufKey {
+    path: PathBuf,
+    size: i32,
+    scale: i32,
+}
+
+/// Decoded pixbufs, along with when each was last used, so that the least recently used pixbuf
+/// can be evicted once the cache is full.
+#[derive(Debug, Default)]
+struct PixbufCache {
+    entries: HashMap<PixbufKey, (Pixbuf, u64)>,
+    clock: u64,
+}
+
+impl PixbufCache {
+    fn get(&mut self, key: &PixbufKey) -> Option<Pixbuf> {
+        self.clock += 1;
+        let (pixbuf, used) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        Some(pixbuf.clone())
+    }
+
+    fn insert(&mut self, key: PixbufKey, pixbuf: Pixbuf) {
+        if self.entries.len() >= PIXBUF_CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(key, (pixbuf, self.clock));
+    }
+}
+
+/// Loads the icon at the given path as a square pixbuf of `size` logical pixels at the given scale
+/// factor.
+///
+/// Decoding (and, for SVGs, rendering) icons is comparatively expensive, and every button for the
+/// same application wants the same image, so the most recently used pixbufs are cached. Failures
+/// aren't cached, so an icon that's installed after it was first looked for will still show up.
+#[tracing::instrument(level = "TRACE")]
+pub fn pixbuf(path: &Path, size: i32, scale: i32) -> Option<Pixbuf> {
+    let key = PixbufKey {
+        path: path.to_path_buf(),
+        size,
+        scale,
+    };
+
+    PIXBUF_CACHE.with(|cache| {
+        if let Some(pixbuf) = cache.borrow_mut().get(&key) {
+            return Some(pixbuf);
+        }
+
+        let physical = size * scale;
+        match Pixbuf::from_file_at_scale(path, physical, physical, true) {
+            Ok(pixbuf) => {
+                cache.borrow_mut().insert(key, pixbuf.clone());
+                Some(pixbuf)
+            }
+            Err(e) => {
+                tracing::info!(%e, ?path, "cannot load icon");
+                None
+            }
+        }
+    })
+}
+
+/// Forgets every decoded pixbuf.
+pub fn clear_pixbufs() {
+    PIXBUF_CACHE.with(|cache| *cache.borrow_mut() = Default::default());
 }
 
 fn lookup(id: &str) -> Option<PathBuf> {
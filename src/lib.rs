@@ -1,37 +1,49 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap, btree_map::Entry},
-    sync::{Arc, LazyLock, Mutex},
+    collections::{BTreeMap, BTreeSet, btree_map::Entry},
+    sync::LazyLock,
+    time::Instant,
 };
 
+use async_channel::{Receiver, Sender};
 use button::Button;
-use config::Config;
+use config::{OtherOutputs, RawConfig};
+use control::{Command, Control, Target};
 use error::Error;
 use futures::StreamExt;
-use niri::{Snapshot, Window};
+use model::{Attention, ButtonModel, Decorations, HintMatch, Launchers, TaskbarModel};
+use niri::Snapshot;
 use notify::EnrichedNotification;
-use output::Matcher;
-use process::Process;
 use state::{Event, State};
 use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan};
 use waybar_cffi::{
     Module,
     gtk::{
-        self, Orientation, gio,
-        glib::MainContext,
+        self, Orientation,
+        glib::{self, Cast, IsA, MainContext},
         traits::{BoxExt, ContainerExt, StyleContextExt, WidgetExt},
     },
     waybar_module,
 };
 
 mod button;
+#[doc(hidden)]
+pub mod cli;
 mod config;
+mod control;
 mod error;
-mod icon;
+mod hint;
+#[doc(hidden)]
+pub mod icon;
+mod model;
 mod niri;
 mod notify;
 mod output;
+mod preview;
 mod process;
+mod record;
+mod rules;
 mod state;
+mod tooltip;
 
 static TRACING: LazyLock<()> = LazyLock::new(|| {
     if let Err(e) = tracing_subscriber::fmt()
@@ -46,13 +58,21 @@ static TRACING: LazyLock<()> = LazyLock::new(|| {
 struct TaskbarModule {}
 
 impl Module for TaskbarModule {
-    type Config = Config;
+    type Config = RawConfig;
 
-    fn init(info: &waybar_cffi::InitInfo, config: Config) -> Self {
+    fn init(info: &waybar_cffi::InitInfo, config: RawConfig) -> Self {
         // Ensure tracing-subscriber is initialised.
         *TRACING;
 
         let module = Self {};
+        let config = match config.parse() {
+            Ok(config) => config,
+            Err(e) => {
+                tracing::error!(%e, "Niri taskbar configuration is invalid");
+                show_error(info, &e);
+                return module;
+            }
+        };
         let state = State::new(config);
 
         let context = MainContext::default();
@@ -81,19 +101,81 @@ async fn init(info: &waybar_cffi::InitInfo, state: State) -> Result<(), Error> {
     Ok(())
 }
 
+/// Shows an error in place of the taskbar, so that it's obvious that something is wrong.
+fn show_error(info: &waybar_cffi::InitInfo, error: &Error) {
+    let label = gtk::Label::new(Some("niri-taskbar: configuration error"));
+    label.set_tooltip_text(Some(&error.to_string()));
+
+    let context = label.style_context();
+    context.add_class("niri-taskbar");
+    context.add_class("error");
+
+    info.get_root_widget().add(&label);
+    label.show();
+}
+
 struct Instance {
+    attention: Attention,
     buttons: BTreeMap<u64, Button>,
+    commands: Receiver<Command>,
     container: gtk::Box,
+    control: Control,
+    decorations: Decorations,
+    /// Incremented each time the next decoration or attention expiry is scheduled, so that earlier
+    /// timers can be ignored.
+    expiry_generation: u64,
+    expiry_rx: Receiver<u64>,
+    expiry_tx: Sender<u64>,
+    filter: output::Filter,
+    focused_output: Option<String>,
+    /// The keys typed so far, if hints are being shown.
+    hint: Option<String>,
+    hint_keys: Receiver<hint::Key>,
+    hint_popup: hint::Popup,
     last_snapshot: Option<Snapshot>,
+    launchers: Launchers,
+    model: TaskbarModel,
+    output_labels: BTreeMap<String, gtk::Label>,
+    remote: gtk::Box,
     state: State,
 }
 
 impl Instance {
     pub fn new(state: State, container: gtk::Box) -> Self {
+        // Windows on other outputs can be shown in their own group, which always sits at the end
+        // of the container. When that's not enabled, it's simply empty.
+        let remote = gtk::Box::new(Orientation::Horizontal, 0);
+        button::add_style_provider(&remote.style_context());
+        remote.style_context().add_class("other-output");
+        container.add(&remote);
+        container.show_all();
+
+        icon::clear_on_theme_change(state.icon_cache());
+
+        let (control, commands) = Control::register();
+        let (expiry_tx, expiry_rx) = async_channel::unbounded();
+        let (hint_popup, hint_keys) = hint::Popup::new(&container);
+
         Self {
+            attention: Default::default(),
             buttons: Default::default(),
+            commands,
             container,
+            control,
+            decorations: Default::default(),
+            expiry_generation: 0,
+            expiry_rx,
+            expiry_tx,
+            filter: Default::default(),
+            focused_output: None,
+            hint: None,
+            hint_keys,
+            hint_popup,
             last_snapshot: None,
+            launchers: Default::default(),
+            model: Default::default(),
+            output_labels: Default::default(),
+            remote,
             state,
         }
     }
@@ -101,35 +183,238 @@ impl Instance {
     pub async fn task(&mut self) {
         // We have to build the output filter here, because until the Glib event loop has run the
         // container hasn't been realised, which means we can't figure out which output we're on.
-        let output_filter = Arc::new(Mutex::new(self.build_output_filter().await));
+        self.filter = self.build_output_filter().await;
+
+        let monitor_changes =
+            output::monitor_changes(&self.container.display()).map(|()| Event::MonitorsChanged);
+        let commands = self.commands.clone().map(Event::Command);
+        let hint_keys = self.hint_keys.clone().map(Event::HintKey);
+        let expiries = self.expiry_rx.clone().map(Event::Expired);
+        let mut stream = Box::pin(futures::stream::select_all([
+            self.state.event_stream().boxed_local(),
+            monitor_changes.boxed_local(),
+            commands.boxed_local(),
+            hint_keys.boxed_local(),
+            expiries.boxed_local(),
+        ]));
 
-        let mut stream = match self.state.event_stream() {
-            Ok(stream) => Box::pin(stream),
-            Err(e) => {
-                tracing::error!(%e, "error starting event stream");
-                return;
-            }
-        };
         while let Some(event) = stream.next().await {
             match event {
+                Event::Command(command) => self.process_command(command).await,
+                Event::Expired(generation) => {
+                    if generation == self.expiry_generation {
+                        self.expire();
+                    }
+                }
+                Event::HintKey(key) => self.process_hint_key(key).await,
+                Event::LauncherEntry(update) => {
+                    self.launchers.update(&update);
+                    self.render();
+                }
                 Event::Notification(notification) => self.process_notification(notification).await,
-                Event::WindowSnapshot(windows) => {
-                    self.process_window_snapshot(windows, output_filter.clone())
-                        .await
+                Event::Niri(niri::Event::Windows(windows)) => self.process_window_snapshot(windows),
+                Event::ConfigReloaded => self.process_config_reload().await,
+                Event::MonitorsChanged => self.refresh_output_filter().await,
+                Event::Niri(niri::Event::Outputs(_)) => self.refresh_output_filter().await,
+                Event::Niri(niri::Event::Workspaces(workspaces)) => {
+                    self.focused_output = workspaces
+                        .into_iter()
+                        .find(|workspace| workspace.is_focused)
+                        .and_then(|workspace| workspace.output);
+                    self.publish();
+                }
+            }
+        }
+    }
+
+    /// Applies a reloaded configuration to the taskbar.
+    ///
+    /// The output filter is rebuilt, since the configured output and the handling of other outputs
+    /// may have changed. Expiring attention and decorations then re-renders the last snapshot so
+    /// that any changed rules are applied to existing buttons, and reschedules attention changes in
+    /// case their behaviour changed.
+    async fn process_config_reload(&mut self) {
+        self.filter = self.build_output_filter().await;
+        self.expire();
+    }
+
+    /// Rebuilds the output filter, and applies it to the last snapshot straight away so that
+    /// windows appear and disappear without waiting for the next window event.
+    async fn refresh_output_filter(&mut self) {
+        self.filter = self.build_output_filter().await;
+        self.render();
+    }
+
+    #[tracing::instrument(level = "DEBUG", skip(self))]
+    async fn process_command(&mut self, command: Command) {
+        match command {
+            Command::Decorate {
+                target,
+                decoration,
+                timeout,
+            } => {
+                let expires = timeout.map(|timeout| Instant::now() + timeout);
+                for id in self.resolve(&target).await {
+                    self.decorations.apply(id, &decoration, expires);
+                }
+                self.expire();
+            }
+            Command::Focus(id) => self.activate_window(id).await,
+            Command::Hint => {
+                if self.hint.is_none() {
+                    self.hint = Some(String::new());
+                    self.render();
+                    self.hint_popup.show();
+                }
+            }
+            Command::ReloadConfig => {
+                if self.state.reload_config().await {
+                    self.process_config_reload().await;
                 }
-                Event::Workspaces(_) => {
-                    // We're just using this as a signal that the outputs may have changed.
-                    let new_filter = self.build_output_filter().await;
-                    *output_filter.lock().expect("output filter lock") = new_filter;
+            }
+            Command::SetUrgent { target, urgent } => {
+                let now = Instant::now();
+                for id in self.resolve(&target).await {
+                    if urgent {
+                        self.attention.mark(id, now);
+                    } else {
+                        self.attention.clear(id);
+                    }
                 }
+                self.expire();
+            }
+        }
+    }
+
+    /// Finds the windows in the last snapshot that the given target refers to.
+    async fn resolve(&self, target: &Target) -> Vec<u64> {
+        let ancestors = match target {
+            Target::Pid(pid) => process::ancestors(*pid).await,
+            _ => Vec::new(),
+        };
+        let Some(windows) = &self.last_snapshot else {
+            return Vec::new();
+        };
+
+        windows
+            .iter()
+            .filter(|window| match target {
+                Target::App(app_id) => window.app_id.as_ref() == Some(app_id),
+                Target::Pid(_) => window
+                    .pid
+                    .is_some_and(|pid| ancestors.contains(&i64::from(pid))),
+                Target::Window(id) => window.id == *id,
+            })
+            .map(|window| window.id)
+            .collect()
+    }
+
+    /// Removes any expired decorations, advances or clears the need for attention, and re-renders,
+    /// then schedules the next expiry, if any.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let attention = match &self.last_snapshot {
+            Some(windows) => self.attention.expire(now, windows, &self.state.config()),
+            None => None,
+        };
+        let decorations = self.decorations.expire(now);
+
+        if let Some(next) = attention.into_iter().chain(decorations).min() {
+            self.expiry_generation += 1;
+            let generation = self.expiry_generation;
+            let tx = self.expiry_tx.clone();
+            glib::timeout_add_local_once(next.saturating_duration_since(now), move || {
+                let _ = tx.try_send(generation);
+            });
+        }
+
+        self.render();
+    }
+
+    #[tracing::instrument(level = "TRACE", skip(self))]
+    async fn process_hint_key(&mut self, key: hint::Key) {
+        let Some(typed) = &mut self.hint else {
+            return;
+        };
+
+        match key {
+            hint::Key::Char(c) => typed.push(c),
+            hint::Key::Backspace => {
+                typed.pop();
+            }
+            hint::Key::Cancel => return self.finish_hint(),
+        }
+
+        let typed = typed.clone();
+        self.render();
+        match self.model.hint_match(&typed) {
+            HintMatch::Window(id) => {
+                self.finish_hint();
+                self.activate_window(id).await;
+            }
+            HintMatch::Partial => {}
+            HintMatch::None => {
+                tracing::debug!(typed, "no window matches the hint");
+                self.finish_hint();
             }
         }
     }
 
+    fn finish_hint(&mut self) {
+        self.hint = None;
+        self.hint_popup.hide();
+        self.render();
+    }
+
+    async fn activate_window(&self, id: u64) {
+        if let Err(e) = self.state.niri().activate_window(id).await {
+            tracing::warn!(%e, id, "error trying to activate window");
+        }
+    }
+
+    /// Publishes what the taskbar is showing to the D-Bus interface.
+    fn publish(&self) {
+        self.control.publish(control::Published {
+            output: self.filter.local().map(String::from),
+            focused: self
+                .focused_output
+                .as_deref()
+                .is_some_and(|output| self.filter.is_local(output)),
+            buttons: self
+                .model
+                .iter()
+                .map(|button| control::Button {
+                    id: button.id,
+                    app_id: button.app_id.clone().unwrap_or_default(),
+                    title: button.title.clone().unwrap_or_default(),
+                    urgent: button.urgent,
+                })
+                .collect(),
+        });
+    }
+
     #[tracing::instrument(level = "DEBUG", skip(self))]
     async fn build_output_filter(&self) -> output::Filter {
-        if self.state.config().show_all_outputs() {
-            return output::Filter::ShowAll;
+        output::Filter::new(
+            self.local_output().await,
+            self.state.config().other_outputs() != OtherOutputs::Hide,
+        )
+    }
+
+    #[tracing::instrument(level = "DEBUG", skip(self), ret)]
+    async fn local_output(&self) -> Option<String> {
+        // If the user has told us which output we're on, then there's nothing to figure out.
+        if let Some(output) = self.state.config().output() {
+            tracing::info!(output, strategy = "config", "using configured output");
+            return Some(output.to_string());
+        }
+        if let Ok(output) = std::env::var(output::OUTPUT_ENV) {
+            tracing::info!(
+                output,
+                strategy = "env",
+                "using output from the environment"
+            );
+            return Some(output);
         }
 
         // OK, so we need to figure out what output we're on. Easy, right?
@@ -143,288 +428,288 @@ impl Instance {
         //
         // We have the Gtk 3 container, though, so that's something — we have to wait until the
         // window has been realised, but that's happened by the time we're in the main loop
-        // callback. The problem is that we're also using Gdk 3, which doesn't expose the connection
-        // name of the monitor in use, which is the only thing we can match against the Niri output
-        // configuration.
-        //
-        // Now, this wouldn't be so bad on its own, because we _can_ get to the `wl_output` via
-        // `gdkwayland`, and version 4 of the core Wayland protocol includes the output name.
-        // Unfortunately, we have no way of accessing Gdk's Wayland connection, and Wayland
-        // identifiers aren't stable across connections, so we can't just connect to Wayland
-        // ourselves and enumerate the outputs. (Trust me, I tried.)
+        // callback. Gdk 3 doesn't expose the connector name on the monitor itself, but it does
+        // still have the deprecated per-screen "plug name" API, which the Wayland backend fills in
+        // from the output name advertised by the compositor. That's the best option when it's
+        // available, since it's exactly what Niri calls the output.
         //
-        // So, until Waybar migrates to Gtk 4, that leaves us without a truly reliable solution.
+        // We can't connect to Wayland ourselves and enumerate the outputs, since Wayland
+        // identifiers aren't stable across connections. (Trust me, I tried.)
         //
-        // What we'll do instead is match up what we can. Niri can tell us everything we want to
-        // know about the output, and Gdk 3 does include things like the output geometry, make, and
-        // model. So we'll match on those and hope for the best.
-        let niri = *self.state.niri();
-        let outputs = match gio::spawn_blocking(move || niri.outputs()).await {
-            Ok(Ok(outputs)) => outputs,
-            Ok(Err(e)) => {
+        // So, if there's no connector name, what we'll do instead is match up what we can. Niri
+        // can tell us everything we want to know about the output, and Gdk 3 does include things
+        // like the output geometry, make, and model. So we'll match on those and hope for the best.
+        let outputs = match self.state.niri().outputs().await {
+            Ok(outputs) => outputs,
+            Err(e) => {
                 tracing::warn!(%e, "cannot get Niri outputs");
-                return output::Filter::ShowAll;
-            }
-            Err(_) => {
-                tracing::error!("error received from gio while waiting for task");
-                return output::Filter::ShowAll;
+                return None;
             }
         };
 
         // If there's only one output, then none of this matching stuff matters anyway.
         if outputs.len() == 1 {
-            return output::Filter::ShowAll;
+            let output = outputs.into_keys().next();
+            tracing::info!(?output, strategy = "single", "only one output is connected");
+            return output;
         }
 
         let Some(window) = self.container.window() else {
             tracing::warn!("cannot get Gdk window for container");
-            return output::Filter::ShowAll;
+            return None;
         };
 
         let display = window.display();
         let Some(monitor) = display.monitor_at_window(&window) else {
             tracing::warn!(display = ?window.display(), geometry = ?window.geometry(), "cannot get monitor for window");
-            return output::Filter::ShowAll;
+            return None;
         };
 
-        for (name, output) in outputs.into_iter() {
-            let matches = output::Matcher::new(&monitor, &output);
-            if matches == Matcher::all() {
-                return output::Filter::Only(name);
+        match output::connector(&monitor) {
+            Some(output) if outputs.contains_key(&output) => {
+                tracing::info!(
+                    output,
+                    strategy = "connector",
+                    "matched Gdk monitor by name"
+                );
+                return Some(output);
+            }
+            Some(output) => {
+                tracing::warn!(output, "Gdk monitor name doesn't match any Niri output");
+            }
+            None => {
+                tracing::debug!("Gdk monitor name is unavailable");
             }
         }
 
-        tracing::warn!(?monitor, "no Niri output matched the Gdk monitor");
-        output::Filter::ShowAll
+        let info = output::MonitorInfo::from_gdk(&monitor);
+        match output::best_match(&info, &outputs) {
+            Some(output) => {
+                tracing::info!(output, strategy = "heuristic", "matched Gdk monitor");
+                Some(output.to_string())
+            }
+            None => {
+                tracing::warn!(?info, "no Niri output matched the Gdk monitor");
+                None
+            }
+        }
     }
 
     #[tracing::instrument(level = "TRACE", skip(self))]
     async fn process_notification(&mut self, notification: Box<EnrichedNotification>) {
-        // We'll try to set the urgent class on the relevant window if we can
-        // figure out which toplevel is associated with the notification.
+        // We'll try to set the urgent class on the relevant window if we can figure out which
+        // toplevel is associated with the notification.
         //
-        // Obviously, for that, we need toplevels.
+        // Obviously, for that, we need toplevels, and only the ones with buttons can show it.
         let Some(toplevels) = &self.last_snapshot else {
             return;
         };
+        let windows = toplevels
+            .iter()
+            .filter(|window| self.model.contains(window.id))
+            .collect::<Vec<_>>();
+
+        let ancestors = match notification.pid() {
+            Some(pid) => process::ancestors(pid).await,
+            None => Vec::new(),
+        };
+        let targets = model::notification_targets(
+            &windows,
+            &ancestors,
+            notification.notification().hints.desktop_entry.as_deref(),
+            &self.state.config(),
+        );
+        if targets.is_empty() {
+            return;
+        }
 
-        if let Some(mut pid) = notification.pid() {
-            tracing::trace!(
-                pid,
-                "got notification with PID; trying to match it to a toplevel"
-            );
-
-            // If we have the sender PID — either from the notification itself,
-            // or D-Bus — then the heuristic we'll use is to walk up from the
-            // sender PID and see if any of the parents are toplevels.
-            //
-            // The easiest way to do that is with a map, which we can build from
-            // the toplevels.
-            let pids = PidWindowMap::new(toplevels.iter());
-
-            // We'll track if we found anything, since we might fall back to
-            // some fuzzy matching.
-            let mut found = false;
-
-            loop {
-                if let Some(window) = pids.get(pid) {
-                    // If the window is already focused, there isn't really much
-                    // to do.
-                    if !window.is_focused {
-                        if let Some(button) = self.buttons.get(&window.id) {
-                            tracing::trace!(
-                                ?button,
-                                ?window,
-                                pid,
-                                "found matching window; setting urgent"
-                            );
-                            button.set_urgent();
-                            found = true;
-                        }
-                    }
-                }
-
-                match Process::new(pid).await {
-                    Ok(Process { ppid }) => {
-                        if let Some(ppid) = ppid {
-                            // Keep walking up.
-                            pid = ppid;
-                        } else {
-                            // There are no more parents.
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        // On error, we'll log but do nothing else: this
-                        // shouldn't be fatal for the bar, since it's possible
-                        // the process has simply already exited.
-                        tracing::info!(pid, %e, "error walking up process tree");
-                        break;
-                    }
-                }
-            }
-
-            // If we marked one or more toplevels as urgent, then we're done.
-            if found {
-                return;
-            }
+        let now = Instant::now();
+        for id in targets {
+            self.attention
+                .notify(id, &notification.notification().summary, now);
         }
+        self.expire();
+    }
 
-        tracing::trace!("no PID in notification, or no match found");
+    #[tracing::instrument(level = "DEBUG", skip(self))]
+    fn process_window_snapshot(&mut self, windows: Snapshot) {
+        self.attention.update(&windows);
+        self.decorations.update(&windows);
+        self.last_snapshot = Some(windows);
+        self.render();
+    }
 
-        // Otherwise, we'll fall back to the desktop entry if we got one, and
-        // see what we can find.
-        //
-        // There are a bunch of things that can get in the way here.
-        // Applications don't necessarily know the application ID they're
-        // registered under on the system: Flatpaks, for instance, have no idea
-        // what the Flatpak actually called them when installed. So we'll do our
-        // best and make some educated guesses, but that's really what it is.
-        if !self.state.config().notifications_use_desktop_entry() {
-            tracing::trace!("use of desktop entries is disabled; no match found");
-            return;
-        }
-        let Some(desktop_entry) = &notification.notification().hints.desktop_entry else {
-            tracing::trace!("no desktop entry found in notification; nothing more to be done");
+    /// Renders the taskbar model for the last snapshot into the container.
+    fn render(&mut self) {
+        let Some(windows) = &self.last_snapshot else {
             return;
         };
+        let config = self.state.config();
+        let mut model = TaskbarModel::new(
+            windows,
+            &config,
+            &self.filter,
+            self.state.icon_cache(),
+            &self.attention,
+            &self.decorations,
+            &self.launchers,
+        );
+        if let Some(typed) = &self.hint {
+            model = model.with_hints(config.hint_keys(), typed);
+        }
 
-        // So we only have to walk the window list once, we'll keep track of the
-        // fuzzy matches we find, even if we don't use them.
-        let use_fuzzy = self.state.config().notifications_use_fuzzy_matching();
-        let mut fuzzy = Vec::new();
-
-        // XXX: do we still need this with fuzzy matching?
-        let mapped = self
-            .state
-            .config()
-            .notifications_app_map(desktop_entry)
-            .unwrap_or(desktop_entry);
-        let mapped_lower = mapped.to_lowercase();
-        let mapped_last_lower = mapped
-            .split('.')
-            .next_back()
-            .unwrap_or_default()
-            .to_lowercase();
-
-        let mut found = false;
-        for window in toplevels.iter() {
-            let Some(app_id) = window.app_id.as_deref() else {
-                continue;
-            };
+        // We need to track which, if any, windows are no longer present.
+        let mut omitted = self.buttons.keys().copied().collect::<BTreeSet<_>>();
 
-            if app_id == mapped {
-                if let Some(button) = self.buttons.get(&window.id) {
-                    tracing::trace!(app_id, ?button, ?window, "toplevel match found via app ID");
-                    button.set_urgent();
-                    found = true;
-                }
-            } else if use_fuzzy {
-                // See if we have a fuzzy match, which we'll basically specify
-                // as "does the app ID match case insensitively, or does the
-                // last component of the app ID match the last component of the
-                // desktop entry?".
-                if app_id.to_lowercase() == mapped_lower {
-                    tracing::trace!(
-                        app_id,
-                        ?window,
-                        "toplevel match found via case-transformed app ID"
-                    );
-                    fuzzy.push(window.id);
-                } else if app_id.contains('.') {
-                    tracing::trace!(
-                        app_id,
-                        ?window,
-                        "toplevel match found via last element of app ID"
-                    );
-                    if let Some(last) = app_id.split('.').next_back() {
-                        if last.to_lowercase() == mapped_last_lower {
-                            fuzzy.push(window.id);
-                        }
-                    }
-                }
+        let mut local = Placement::new(&self.container);
+        self.update_buttons(&model.buttons, &mut local, &mut omitted);
+
+        let remote_box = self.remote.clone();
+        let mut remote = Placement::new(&remote_box);
+        let mut labelled = BTreeSet::new();
+        for group in model.other_outputs.iter() {
+            if config.label_other_outputs() {
+                let label = self
+                    .output_labels
+                    .entry(group.output.clone())
+                    .or_insert_with(|| {
+                        let label = gtk::Label::new(Some(group.output.as_str()));
+                        label.style_context().add_class("output-label");
+                        label.show();
+                        remote.add(&label);
+                        label
+                    });
+                remote.place(&*label);
+                labelled.insert(group.output.clone());
             }
+
+            self.update_buttons(&group.buttons, &mut remote, &mut omitted);
         }
 
-        if !found {
-            for id in fuzzy.into_iter() {
-                if let Some(button) = self.buttons.get(&id) {
-                    button.set_urgent();
-                }
+        // Remove any output labels that are no longer needed.
+        self.output_labels.retain(|output, label| {
+            let keep = labelled.contains(output);
+            if !keep {
+                remote_box.remove(&*label);
+            }
+            keep
+        });
+
+        // Remove any windows that no longer exist.
+        for id in omitted.into_iter() {
+            if let Some(button) = self.buttons.remove(&id) {
+                detach(button.widget());
+                self.state.previews().forget(id);
             }
         }
+
+        // The remote group always sits at the end.
+        local.place(&self.remote);
+
+        self.model = model;
+        self.publish();
     }
 
-    #[tracing::instrument(level = "DEBUG", skip(self))]
-    async fn process_window_snapshot(
+    /// Creates or updates the buttons for the given models within the given parent, in order.
+    fn update_buttons(
         &mut self,
-        windows: Snapshot,
-        filter: Arc<Mutex<output::Filter>>,
+        models: &[ButtonModel],
+        parent: &mut Placement,
+        omitted: &mut BTreeSet<u64>,
     ) {
-        // We need to track which, if any, windows are no longer present.
-        let mut omitted = self.buttons.keys().copied().collect::<BTreeSet<_>>();
-
-        for window in windows.iter().filter(|window| {
-            filter
-                .lock()
-                .expect("output filter lock")
-                .should_show(window.output().unwrap_or_default())
-        }) {
-            let button = match self.buttons.entry(window.id) {
-                Entry::Occupied(entry) => entry.into_mut(),
+        for model in models.iter() {
+            let button = match self.buttons.entry(model.id) {
+                Entry::Occupied(entry) => {
+                    let button = entry.into_mut();
+                    button.update(model);
+                    button
+                }
                 Entry::Vacant(entry) => {
-                    let button = Button::new(&self.state, window);
-
-                    // Implicitly adding the button widget to the box as we create it simplifies
-                    // reordering, since it means we can just do it as we go.
-                    self.container.add(button.widget());
+                    // Only new widgets need showing: showing the whole container on every render
+                    // would walk every button, and renders happen as often as attention blinks.
+                    let button = Button::new(&self.state, model);
+                    button.widget().show_all();
                     entry.insert(button)
                 }
             };
 
-            // Update the window properties.
-            button.set_focus(window.is_focused);
-            button.set_title(window.title.as_deref());
+            // Implicitly adding the button widget to the box as we go simplifies reordering, and
+            // also handles windows moving between the local and remote groups.
+            let widget = button.widget();
+            parent.add(widget);
 
             // Ensure we don't remove this button from the container.
-            omitted.remove(&window.id);
+            omitted.remove(&model.id);
 
-            // Since we get the windows in order in the snapshot, we can just
-            // push this to the back and then let other widgets push in front as
-            // we iterate.
-            self.container.reorder_child(button.widget(), -1);
+            // The model is already in taskbar order, so we can just place each one after the
+            // last.
+            parent.place(widget);
         }
+    }
+}
 
-        // Remove any windows that no longer exist.
-        for id in omitted.into_iter() {
-            if let Some(button) = self.buttons.remove(&id) {
-                self.container.remove(button.widget());
-            }
-        }
+/// Places widgets within a box in order during a render.
+///
+/// The box's children are only fetched once, and then tracked as widgets are added and moved,
+/// since fetching them from Gtk for every widget makes each render quadratic in the number of
+/// buttons.
+///
+/// Widgets that are removed from the box while this exists are still tracked, but since they were
+/// never placed, they can only ever appear after the widgets that were, and so can't affect where
+/// later widgets end up.
+struct Placement {
+    parent: gtk::Box,
+    children: Vec<gtk::Widget>,
+    position: usize,
+}
 
-        // Ensure everything is rendered.
-        self.container.show_all();
+impl Placement {
+    fn new(parent: &gtk::Box) -> Self {
+        Self {
+            parent: parent.clone(),
+            children: parent.children(),
+            position: 0,
+        }
+    }
 
-        // Update the last snapshot.
-        self.last_snapshot = Some(windows);
+    /// Adds a widget to the end of the box, removing it from any other container first.
+    ///
+    /// Widgets that are already in the box are left where they are.
+    fn add(&mut self, widget: &impl IsA<gtk::Widget>) {
+        if widget.parent().as_ref() != Some(self.parent.upcast_ref()) {
+            detach(widget);
+            self.parent.add(widget);
+            self.children.push(widget.clone().upcast());
+        }
     }
-}
 
-/// A basic map of PIDs to windows.
-///
-/// Windows that don't have a PID are ignored, since we can't match on them
-/// anyway. (Also, how does that happen?)
-struct PidWindowMap<'a>(HashMap<i64, &'a Window>);
-
-impl<'a> PidWindowMap<'a> {
-    fn new(iter: impl Iterator<Item = &'a Window>) -> Self {
-        Self(
-            iter.filter_map(|window| window.pid.map(|pid| (i64::from(pid), window)))
-                .collect(),
-        )
+    /// Moves a widget to the next position within the box.
+    ///
+    /// Gtk queues a resize whenever a child is reordered, even if it doesn't actually move, so we
+    /// check first to avoid relayouts when the order hasn't changed.
+    fn place(&mut self, widget: &impl IsA<gtk::Widget>) {
+        let widget = widget.upcast_ref::<gtk::Widget>();
+
+        if self.children.get(self.position) != Some(widget) {
+            self.parent.reorder_child(widget, self.position as i32);
+
+            if let Some(current) = self.children.iter().position(|child| child == widget) {
+                self.children.remove(current);
+            }
+            let position = self.position.min(self.children.len());
+            self.children.insert(position, widget.clone());
+        }
+
+        self.position += 1;
     }
+}
 
-    fn get(&self, pid: i64) -> Option<&'a Window> {
-        self.0.get(&pid).copied()
+/// Removes a widget from whichever container it's in, if any.
+fn detach(widget: &impl IsA<gtk::Widget>) {
+    if let Some(parent) = widget
+        .parent()
+        .and_then(|parent| parent.downcast::<gtk::Container>().ok())
+    {
+        parent.remove(widget);
     }
 }
@@ -0,0 +1,1243 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    config::{AttentionBehaviour, Config, OtherOutputs},
+    icon,
+    niri::{Snapshot, Window},
+    notify::LauncherEntryUpdate,
+    output::Filter,
+    rules::Outcome,
+};
+
+/// The contents of the taskbar for a window snapshot, independent of Gtk.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaskbarModel {
+    /// The buttons for windows on the taskbar's output, or on every output if they're not shown
+    /// separately, in order.
+    pub buttons: Vec<ButtonModel>,
+
+    /// The buttons for windows on other outputs, grouped by output, if they're shown separately.
+    pub other_outputs: Vec<OutputGroup>,
+}
+
+impl TaskbarModel {
+    /// Builds the taskbar model for the given snapshot.
+    ///
+    /// This applies the output filter and window rules, then works out the classes, label, icon,
+    /// and tooltip for each button in the same way the taskbar does.
+    pub fn new(
+        windows: &Snapshot,
+        config: &Config,
+        filter: &Filter,
+        icons: &icon::Cache,
+        attention: &Attention,
+        decorations: &Decorations,
+        launchers: &Launchers,
+    ) -> Self {
+        let rules = config.rules();
+        let launchers = launchers.by_window(&windows.iter().collect::<Vec<_>>(), config);
+        let visible = windows
+            .iter()
+            .filter(|window| filter.should_show(window.output().unwrap_or_default()))
+            .map(|window| (window, rules.evaluate(window)))
+            .filter(|(_, outcome)| !outcome.hide);
+
+        // If windows on other outputs are being shown separately, we'll split them out, and group
+        // them by output. The sort is stable, so they stay in snapshot order within each output.
+        let (local, mut remote): (Vec<_>, Vec<_>) =
+            visible.partition(|(window, _)| match config.other_outputs() {
+                OtherOutputs::Separate => filter.is_local(window.output().unwrap_or_default()),
+                _ => true,
+            });
+        remote.sort_by(|(a, _), (b, _)| a.output().cmp(&b.output()));
+
+        let build = |group: &[(&Window, Outcome)]| {
+            buttons(
+                group,
+                config,
+                filter,
+                icons,
+                attention,
+                decorations,
+                &launchers,
+            )
+        };
+        Self {
+            buttons: build(&local),
+            other_outputs: remote
+                .iter()
+                .chunk_by(|(window, _)| window.output().unwrap_or_default())
+                .into_iter()
+                .map(|(output, group)| OutputGroup {
+                    output: output.to_string(),
+                    buttons: build(&group.cloned().collect::<Vec<_>>()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Assigns a hint to each button, in taskbar order, so that windows can be picked from the
+    /// keyboard.
+    ///
+    /// Only buttons with hints that start with the keys typed so far keep their hints; the rest
+    /// are marked as excluded.
+    pub fn with_hints(mut self, keys: &[char], typed: &str) -> Self {
+        let count = self.iter().count();
+        for (button, hint) in self.iter_mut().zip(hints(keys, count)) {
+            if hint.starts_with(typed) {
+                button.classes.push("hint".to_string());
+                button.hint = Some(hint);
+            } else {
+                button.classes.push("hint-excluded".to_string());
+            }
+        }
+
+        self
+    }
+
+    /// Finds the window picked by the keys typed so far, once hints have been assigned.
+    pub fn hint_match(&self, typed: &str) -> HintMatch {
+        let mut hinted = self
+            .iter()
+            .filter_map(|button| Some((button.id, button.hint.as_deref()?)))
+            .peekable();
+        if hinted.peek().is_none() {
+            return HintMatch::None;
+        }
+
+        match hinted.find(|(_, hint)| *hint == typed) {
+            Some((id, _)) => HintMatch::Window(id),
+            None => HintMatch::Partial,
+        }
+    }
+
+    /// Checks if there's a button for the given window anywhere in the taskbar.
+    pub fn contains(&self, id: u64) -> bool {
+        self.iter().any(|button| button.id == id)
+    }
+
+    /// Iterates over every button, in taskbar order.
+    pub fn iter(&self) -> impl Iterator<Item = &ButtonModel> {
+        self.buttons.iter().chain(
+            self.other_outputs
+                .iter()
+                .flat_map(|group| group.buttons.iter()),
+        )
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut ButtonModel> {
+        self.buttons.iter_mut().chain(
+            self.other_outputs
+                .iter_mut()
+                .flat_map(|group| group.buttons.iter_mut()),
+        )
+    }
+}
+
+/// The result of matching typed keys against the hints in a taskbar model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HintMatch {
+    /// The keys typed so far are a complete hint for the given window.
+    Window(u64),
+    /// The keys typed so far are the start of one or more hints.
+    Partial,
+    /// No hint starts with the keys typed so far.
+    None,
+}
+
+/// A group of buttons for windows on another output.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputGroup {
+    pub output: String,
+    pub buttons: Vec<ButtonModel>,
+}
+
+/// A single taskbar button.
+#[derive(Debug, Clone, Serialize)]
+pub struct ButtonModel {
+    pub id: u64,
+    pub app_id: Option<String>,
+    pub title: Option<String>,
+    pub workspace_idx: u8,
+    pub output: Option<String>,
+    pub classes: Vec<String>,
+    pub label: Option<String>,
+    pub icon: Option<PathBuf>,
+    pub notifications: Vec<String>,
+    pub urgent: bool,
+    pub tooltip: Option<Tooltip>,
+    pub hint: Option<String>,
+    pub badge: Option<String>,
+    pub progress: Option<f64>,
+}
+
+/// The tooltip for a button, which is Pango markup if it was rendered from a template.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tooltip {
+    Markup(String),
+    Text(String),
+}
+
+/// Windows that need attention, keyed by window ID, along with the summaries of any notifications
+/// that caused it.
+///
+/// Windows can also be marked as needing attention without a notification. Either way, it's
+/// cleared once the window is focused, or earlier if the app's [`AttentionBehaviour`] says so.
+#[derive(Debug, Clone, Default)]
+pub struct Attention(BTreeMap<u64, Urgency>);
+
+#[derive(Debug, Clone)]
+struct Urgency {
+    notifications: Vec<String>,
+    /// When the window last asked for attention, which is when any blinking or auto-clearing
+    /// starts from.
+    since: Instant,
+    /// True if the button is in the "off" half of a blink, when it's drawn as if it weren't
+    /// urgent. Blinking starts with the "on" half, so the button stands out straight away.
+    blink: bool,
+}
+
+/// How long each half of a blink lasts.
+const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+impl Attention {
+    /// Records a notification for the given window, which arrived at the given time.
+    pub fn notify(&mut self, id: u64, summary: &str, now: Instant) {
+        self.mark(id, now);
+        if let Some(urgency) = self.0.get_mut(&id) {
+            urgency.notifications.push(summary.to_string());
+        }
+    }
+
+    /// Marks the given window as needing attention as of the given time, without a notification.
+    pub fn mark(&mut self, id: u64, now: Instant) {
+        self.0
+            .entry(id)
+            .and_modify(|urgency| urgency.since = now)
+            .or_insert_with(|| Urgency {
+                notifications: Vec::new(),
+                since: now,
+                blink: false,
+            });
+    }
+
+    /// Clears the given window's need for attention, along with any notifications.
+    pub fn clear(&mut self, id: u64) {
+        self.0.remove(&id);
+    }
+
+    /// Clears the need for attention for any windows that are now focused or no longer exist.
+    pub fn update(&mut self, windows: &Snapshot) {
+        let unseen = windows
+            .iter()
+            .filter(|window| !window.is_focused)
+            .map(|window| window.id)
+            .collect::<BTreeSet<_>>();
+        self.0.retain(|id, _| unseen.contains(id));
+    }
+
+    /// Advances any blinking and clears the need for attention from windows whose time is up as
+    /// of the given time, returning when the next change is due, if ever.
+    pub fn expire(&mut self, now: Instant, windows: &Snapshot, config: &Config) -> Option<Instant> {
+        let app_ids = windows
+            .iter()
+            .map(|window| (window.id, window.app_id.as_deref()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut next = None;
+        self.0.retain(|id, urgency| {
+            let elapsed = now.saturating_duration_since(urgency.since);
+            let (keep, due) = match config.attention(app_ids.get(id).copied().flatten()) {
+                AttentionBehaviour::AutoClear(duration) => {
+                    (elapsed < duration, Some(urgency.since + duration))
+                }
+                AttentionBehaviour::Blink(duration) if elapsed < duration => {
+                    let half = (elapsed.as_millis() / BLINK_INTERVAL.as_millis()) as u32;
+                    urgency.blink = !half.is_multiple_of(2);
+                    (true, Some(urgency.since + BLINK_INTERVAL * (half + 1)))
+                }
+                AttentionBehaviour::Blink(_) | AttentionBehaviour::Sticky => {
+                    urgency.blink = false;
+                    (true, None)
+                }
+            };
+
+            if keep {
+                next = next.into_iter().chain(due).min();
+            }
+            keep
+        });
+
+        next
+    }
+
+    fn get(&self, id: u64) -> Option<&[String]> {
+        self.0
+            .get(&id)
+            .map(|urgency| urgency.notifications.as_slice())
+    }
+
+    fn is_blinking(&self, id: u64) -> bool {
+        self.0.get(&id).is_some_and(|urgency| urgency.blink)
+    }
+}
+
+/// A change to the decorations on a window, made by an external script.
+#[derive(Debug, Clone)]
+pub enum Decoration {
+    /// Sets the badge text, or removes it.
+    Badge(Option<String>),
+    /// Adds a CSS class.
+    Class(String),
+    /// Removes every decoration.
+    Clear,
+    /// Sets the progress fraction, between 0 and 1, or removes it.
+    Progress(Option<f64>),
+}
+
+/// Decorations attached to windows by external scripts, keyed by window ID.
+///
+/// Each decoration can expire independently. Decorations are removed when their window closes,
+/// but unlike the need for attention, they aren't cleared when the window is focused.
+#[derive(Debug, Clone, Default)]
+pub struct Decorations(BTreeMap<u64, WindowDecorations>);
+
+#[derive(Debug, Clone, Default)]
+struct WindowDecorations {
+    badge: Option<Expiring<String>>,
+    classes: BTreeMap<String, Option<Instant>>,
+    progress: Option<Expiring<f64>>,
+}
+
+#[derive(Debug, Clone)]
+struct Expiring<T> {
+    value: T,
+    expires: Option<Instant>,
+}
+
+impl Decorations {
+    /// Applies a change to the given window's decorations, which expires at the given time, if
+    /// any.
+    pub fn apply(&mut self, id: u64, decoration: &Decoration, expires: Option<Instant>) {
+        if let Decoration::Clear = decoration {
+            self.0.remove(&id);
+            return;
+        }
+
+        let window = self.0.entry(id).or_default();
+        match decoration {
+            Decoration::Badge(badge) => {
+                window.badge = badge.clone().map(|value| Expiring { value, expires });
+            }
+            Decoration::Class(class) => {
+                window.classes.insert(class.clone(), expires);
+            }
+            Decoration::Clear => {}
+            Decoration::Progress(progress) => {
+                window.progress = progress.map(|value| Expiring {
+                    value: value.clamp(0.0, 1.0),
+                    expires,
+                });
+            }
+        }
+    }
+
+    /// Removes any decorations that have expired as of the given time, returning when the next
+    /// one will expire, if ever.
+    pub fn expire(&mut self, now: Instant) -> Option<Instant> {
+        let live = |expires: &Option<Instant>| expires.is_none_or(|expires| expires > now);
+        for window in self.0.values_mut() {
+            window.badge.take_if(|badge| !live(&badge.expires));
+            window.classes.retain(|_, expires| live(expires));
+            window.progress.take_if(|progress| !live(&progress.expires));
+        }
+        self.0.retain(|_, window| {
+            window.badge.is_some() || !window.classes.is_empty() || window.progress.is_some()
+        });
+
+        self.0
+            .values()
+            .flat_map(|window| {
+                window
+                    .badge
+                    .as_ref()
+                    .map(|badge| badge.expires)
+                    .into_iter()
+                    .chain(window.classes.values().copied())
+                    .chain(window.progress.as_ref().map(|progress| progress.expires))
+            })
+            .flatten()
+            .min()
+    }
+
+    /// Removes the decorations for any windows that no longer exist.
+    pub fn update(&mut self, windows: &Snapshot) {
+        let ids = windows
+            .iter()
+            .map(|window| window.id)
+            .collect::<BTreeSet<_>>();
+        self.0.retain(|id, _| ids.contains(id));
+    }
+}
+
+/// Counts and progress published by applications through the Unity launcher API, keyed by desktop
+/// entry.
+///
+/// Unlike decorations, these belong to the application rather than a window, so they're shown on
+/// every window the desktop entry matches.
+#[derive(Debug, Clone, Default)]
+pub struct Launchers(BTreeMap<String, Launcher>);
+
+#[derive(Debug, Clone, Default)]
+struct Launcher {
+    count: i64,
+    count_visible: bool,
+    progress: f64,
+    progress_visible: bool,
+}
+
+impl Launchers {
+    /// Applies an update from an application, which only includes the properties that changed.
+    pub fn update(&mut self, update: &LauncherEntryUpdate) {
+        let launcher = self.0.entry(update.desktop_entry.clone()).or_default();
+        if let Some(count) = update.count {
+            launcher.count = count;
+        }
+        if let Some(count_visible) = update.count_visible {
+            launcher.count_visible = count_visible;
+        }
+        if let Some(progress) = update.progress {
+            launcher.progress = progress.clamp(0.0, 1.0);
+        }
+        if let Some(progress_visible) = update.progress_visible {
+            launcher.progress_visible = progress_visible;
+        }
+    }
+
+    // Works out which launcher entry applies to each window.
+    fn by_window(&self, windows: &[&Window], config: &Config) -> BTreeMap<u64, &Launcher> {
+        self.0
+            .iter()
+            .flat_map(|(desktop_entry, launcher)| {
+                desktop_entry_targets(windows, desktop_entry, config)
+                    .into_iter()
+                    .map(move |id| (id, launcher))
+            })
+            .collect()
+    }
+}
+
+fn buttons(
+    group: &[(&Window, Outcome)],
+    config: &Config,
+    filter: &Filter,
+    icons: &icon::Cache,
+    attention: &Attention,
+    decorations: &Decorations,
+    launchers: &BTreeMap<u64, &Launcher>,
+) -> Vec<ButtonModel> {
+    group
+        .iter()
+        .enumerate()
+        .map(|(i, (window, outcome))| {
+            // Figure out where the window sits relative to its neighbours, since we only know that
+            // while walking the snapshot.
+            let workspace_id = window.workspace().id;
+            let first = i == 0 || group[i - 1].0.workspace().id != workspace_id;
+            let last = group
+                .get(i + 1)
+                .is_none_or(|(next, _)| next.workspace().id != workspace_id);
+            let other_output = !filter.is_local(window.output().unwrap_or_default());
+            let urgent = attention.get(window.id).is_some();
+            let notifications = attention.get(window.id).unwrap_or_default().to_vec();
+
+            let mut classes = Vec::new();
+            for (class, enabled) in [
+                ("focused", window.is_focused),
+                ("floating", window.is_floating),
+                ("tiled", !window.is_floating),
+                ("on-active-workspace", window.workspace().is_active),
+                ("on-other-output", other_output),
+                ("first-in-workspace", first),
+                ("last-in-workspace", last),
+                ("urgent", urgent),
+                ("blink", attention.is_blinking(window.id)),
+            ] {
+                if enabled {
+                    classes.push(class.to_string());
+                }
+            }
+
+            if let Some(app_id) = &window.app_id {
+                classes.push(app_class(app_id));
+                if let Some(title) = window.title.as_deref() {
+                    classes.extend(config.app_matches(app_id, title).map(String::from));
+                }
+            }
+            classes.extend(outcome.classes.iter().map(|class| class.to_string()));
+
+            let decorations = decorations.0.get(&window.id);
+            let launcher = launchers.get(&window.id);
+            if let Some(decorations) = decorations {
+                classes.extend(decorations.classes.keys().cloned());
+            }
+
+            // Icon overrides can either be a path or an icon name to look up.
+            let icon = match outcome.icon {
+                Some(icon) if Path::new(icon).is_absolute() => Some(PathBuf::from(icon)),
+                Some(icon) => icons.lookup(icon),
+                None => window.app_id.as_deref().and_then(|id| icons.lookup(id)),
+            };
+
+            let tooltip = match config.tooltip() {
+                Some(template) => Some(Tooltip::Markup(template.render(window, &notifications))),
+                None => window.title.clone().map(Tooltip::Text),
+            };
+
+            ButtonModel {
+                id: window.id,
+                app_id: window.app_id.clone(),
+                title: window.title.clone(),
+                workspace_idx: window.workspace().idx,
+                output: window.output().map(String::from),
+                classes,
+                label: outcome.label.map(String::from),
+                icon,
+                notifications,
+                urgent,
+                tooltip,
+                hint: None,
+                // Decorations set by scripts take precedence over what the application asked for.
+                badge: decorations
+                    .and_then(|decorations| decorations.badge.as_ref())
+                    .map(|badge| badge.value.clone())
+                    .or_else(|| {
+                        launcher
+                            .filter(|launcher| launcher.count_visible && launcher.count > 0)
+                            .map(|launcher| launcher.count.to_string())
+                    }),
+                progress: decorations
+                    .and_then(|decorations| decorations.progress.as_ref())
+                    .map(|progress| progress.value)
+                    .or_else(|| {
+                        launcher
+                            .filter(|launcher| launcher.progress_visible)
+                            .map(|launcher| launcher.progress)
+                    }),
+            }
+        })
+        .collect()
+}
+
+/// Returns the given number of hints built from the given keys.
+///
+/// Every hint is the same length, which is the shortest that gives each window its own hint. That
+/// way, no hint is the start of another, and a window is picked as soon as its hint is typed.
+fn hints(keys: &[char], count: usize) -> Vec<String> {
+    let mut len = 1;
+    while keys.len().pow(len) < count {
+        len += 1;
+    }
+
+    (0..count)
+        .map(|mut n| {
+            let mut hint = vec![keys[0]; len as usize];
+            for c in hint.iter_mut().rev() {
+                *c = keys[n % keys.len()];
+                n /= keys.len();
+            }
+            hint.into_iter().collect()
+        })
+        .collect()
+}
+
+/// Finds the windows that a notification should be attributed to.
+///
+/// `ancestors` is the sending process followed by its ancestors, if the notification included a
+/// PID. The heuristic we use is to walk up from the sender and see if any of the parents are
+/// windows. If nothing matches that way, we fall back to the desktop entry, if there is one.
+pub fn notification_targets(
+    windows: &[&Window],
+    ancestors: &[i64],
+    desktop_entry: Option<&str>,
+    config: &Config,
+) -> Vec<u64> {
+    // Windows that don't have a PID are ignored, since we can't match on them anyway. (Also, how
+    // does that happen?)
+    let pids = windows
+        .iter()
+        .filter_map(|window| window.pid.map(|pid| (i64::from(pid), *window)))
+        .collect::<BTreeMap<_, _>>();
+
+    // If the window is already focused, there isn't really much to do.
+    let targets = ancestors
+        .iter()
+        .filter_map(|pid| pids.get(pid))
+        .filter(|window| !window.is_focused)
+        .map(|window| window.id)
+        .collect::<Vec<_>>();
+    if !targets.is_empty() {
+        tracing::trace!(?targets, "found matching windows via the process tree");
+        return targets;
+    }
+
+    tracing::trace!("no PID in notification, or no match found");
+
+    // There are a bunch of things that can get in the way here. Applications don't necessarily
+    // know the application ID they're registered under on the system: Flatpaks, for instance,
+    // have no idea what the Flatpak actually called them when installed. So we'll do our best and
+    // make some educated guesses, but that's really what it is.
+    if !config.notifications_use_desktop_entry() {
+        tracing::trace!("use of desktop entries is disabled; no match found");
+        return Vec::new();
+    }
+    let Some(desktop_entry) = desktop_entry else {
+        tracing::trace!("no desktop entry found in notification; nothing more to be done");
+        return Vec::new();
+    };
+
+    desktop_entry_targets(windows, desktop_entry, config)
+}
+
+/// Finds the windows belonging to the application with the given desktop entry.
+///
+/// Exact matches on the app ID (after applying the notification app map) are preferred, falling
+/// back to fuzzy matches if they're enabled.
+pub fn desktop_entry_targets(
+    windows: &[&Window],
+    desktop_entry: &str,
+    config: &Config,
+) -> Vec<u64> {
+    // So we only have to walk the window list once, we'll keep track of the fuzzy matches we
+    // find, even if we don't use them.
+    let use_fuzzy = config.notifications_use_fuzzy_matching();
+    let mut exact = Vec::new();
+    let mut fuzzy = Vec::new();
+
+    // XXX: do we still need this with fuzzy matching?
+    let mapped = config
+        .notifications_app_map(desktop_entry)
+        .unwrap_or(desktop_entry);
+    let mapped_lower = mapped.to_lowercase();
+    let mapped_last_lower = mapped
+        .split('.')
+        .next_back()
+        .unwrap_or_default()
+        .to_lowercase();
+
+    for window in windows.iter() {
+        let Some(app_id) = window.app_id.as_deref() else {
+            continue;
+        };
+
+        if app_id == mapped {
+            tracing::trace!(app_id, ?window, "window match found via app ID");
+            exact.push(window.id);
+        } else if use_fuzzy {
+            // See if we have a fuzzy match, which we'll basically specify as "does the app ID
+            // match case insensitively, or does the last component of the app ID match the last
+            // component of the desktop entry?".
+            if app_id.to_lowercase() == mapped_lower {
+                tracing::trace!(
+                    app_id,
+                    ?window,
+                    "window match found via case-transformed app ID"
+                );
+                fuzzy.push(window.id);
+            } else if app_id.contains('.') {
+                if let Some(last) = app_id.split('.').next_back() {
+                    if last.to_lowercase() == mapped_last_lower {
+                        tracing::trace!(
+                            app_id,
+                            ?window,
+                            "window match found via last element of app ID"
+                        );
+                        fuzzy.push(window.id);
+                    }
+                }
+            }
+        }
+    }
+
+    if exact.is_empty() { fuzzy } else { exact }
+}
+
+/// Returns the CSS class for the given app ID.
+///
+/// App IDs are often reverse DNS names, which aren't valid CSS identifiers, so anything other than
+/// ASCII alphanumerics, hyphens, and underscores is replaced with a hyphen.
+pub fn app_class(app_id: &str) -> String {
+    let sanitised: String = app_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect();
+
+    format!("app-{sanitised}")
+}
+
+#[cfg(test)]
+mod tests {
+    use niri_ipc::Window as NiriWindow;
+
+    use crate::niri::testing::{snapshot, window, workspace};
+
+    use super::*;
+
+    fn config(json: &str) -> Config {
+        serde_jsonc::from_str(json).unwrap()
+    }
+
+    fn app_window(id: u64, workspace_id: u64, column: usize, app_id: &str) -> NiriWindow {
+        NiriWindow {
+            app_id: Some(app_id.to_string()),
+            ..window(id, workspace_id, column)
+        }
+    }
+
+    // Two workspaces on DP-1, the first of which is active, and an active workspace on HDMI-A-1.
+    fn windows() -> Snapshot {
+        let mut active = workspace(10, 1, "DP-1");
+        active.is_active = true;
+        let mut other = workspace(20, 1, "HDMI-A-1");
+        other.is_active = true;
+
+        snapshot(
+            vec![
+                NiriWindow {
+                    is_focused: true,
+                    title: Some("~/src".to_string()),
+                    ..app_window(1, 10, 1, "foot")
+                },
+                app_window(2, 10, 2, "org.mozilla.firefox"),
+                NiriWindow {
+                    is_floating: true,
+                    ..app_window(3, 11, 1, "org.gnome.Nautilus")
+                },
+                app_window(4, 20, 1, "Slack"),
+                app_window(5, 20, 2, "foot"),
+            ],
+            vec![active, workspace(11, 2, "DP-1"), other],
+        )
+    }
+
+    fn model(windows: &Snapshot, config: &Config, filter: &Filter) -> TaskbarModel {
+        TaskbarModel::new(
+            windows,
+            config,
+            filter,
+            &icon::Cache::default(),
+            &Attention::default(),
+            &Decorations::default(),
+            &Launchers::default(),
+        )
+    }
+
+    fn ids(buttons: &[ButtonModel]) -> Vec<u64> {
+        buttons.iter().map(|button| button.id).collect()
+    }
+
+    fn groups(model: &TaskbarModel) -> Vec<(&str, Vec<u64>)> {
+        model
+            .other_outputs
+            .iter()
+            .map(|group| (group.output.as_str(), ids(&group.buttons)))
+            .collect()
+    }
+
+    fn classes(model: &TaskbarModel, id: u64) -> Vec<&str> {
+        model
+            .iter()
+            .find(|button| button.id == id)
+            .unwrap()
+            .classes
+            .iter()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn other_outputs_are_hidden_by_default() {
+        let model = model(
+            &windows(),
+            &Config::default(),
+            &Filter::new(Some("DP-1".to_string()), false),
+        );
+
+        assert_eq!(ids(&model.buttons), vec![1, 2, 3]);
+        assert!(model.other_outputs.is_empty());
+    }
+
+    #[test]
+    fn unknown_output_shows_everything() {
+        let model = model(&windows(), &Config::default(), &Filter::default());
+
+        assert_eq!(ids(&model.buttons), vec![1, 4, 2, 5, 3]);
+        assert!(model.other_outputs.is_empty());
+    }
+
+    #[test]
+    fn other_outputs_can_be_shown_inline() {
+        let model = model(
+            &windows(),
+            &config(r#"{ "other_outputs": "show" }"#),
+            &Filter::new(Some("DP-1".to_string()), true),
+        );
+
+        assert_eq!(ids(&model.buttons), vec![1, 4, 2, 5, 3]);
+        assert!(model.other_outputs.is_empty());
+    }
+
+    #[test]
+    fn other_outputs_can_be_grouped_separately() {
+        let model = model(
+            &windows(),
+            &config(r#"{ "other_outputs": "separate" }"#),
+            &Filter::new(Some("HDMI-A-1".to_string()), true),
+        );
+
+        assert_eq!(ids(&model.buttons), vec![4, 5]);
+        assert_eq!(groups(&model), vec![("DP-1", vec![1, 2, 3])]);
+        assert_eq!(model.iter().count(), 5);
+    }
+
+    #[test]
+    fn rules_can_hide_windows() {
+        let model = model(
+            &windows(),
+            &config(r#"{ "rules": [{ "app_id": "^foot$", "hide": true }] }"#),
+            &Filter::default(),
+        );
+
+        assert_eq!(ids(&model.buttons), vec![4, 2, 3]);
+        assert!(!model.contains(1));
+        assert!(model.contains(2));
+    }
+
+    #[test]
+    fn button_classes() {
+        let model = model(
+            &windows(),
+            &config(
+                r#"{
+                    "other_outputs": "separate",
+                    "apps": { "foot": [{ "match": "^~", "class": "home" }] },
+                    "rules": [{ "app_id": "firefox", "class": "browser" }]
+                }"#,
+            ),
+            &Filter::new(Some("DP-1".to_string()), true),
+        );
+
+        assert_eq!(
+            classes(&model, 1),
+            vec![
+                "focused",
+                "tiled",
+                "on-active-workspace",
+                "first-in-workspace",
+                "app-foot",
+                "home",
+            ]
+        );
+        assert_eq!(
+            classes(&model, 2),
+            vec![
+                "tiled",
+                "on-active-workspace",
+                "last-in-workspace",
+                "app-org-mozilla-firefox",
+                "browser",
+            ]
+        );
+        assert_eq!(
+            classes(&model, 3),
+            vec![
+                "floating",
+                "first-in-workspace",
+                "last-in-workspace",
+                "app-org-gnome-nautilus",
+            ]
+        );
+        // The title doesn't match the app's pattern, so there's no "home" class.
+        assert_eq!(
+            classes(&model, 5),
+            vec![
+                "tiled",
+                "on-active-workspace",
+                "on-other-output",
+                "last-in-workspace",
+                "app-foot",
+            ]
+        );
+    }
+
+    #[test]
+    fn attention_and_decoration_classes() {
+        let windows = windows();
+        let mut attention = Attention::default();
+        attention.notify(2, "New message", Instant::now());
+        let mut decorations = Decorations::default();
+        decorations.apply(2, &Decoration::Class("building".to_string()), None);
+        decorations.apply(2, &Decoration::Badge(Some("3".to_string())), None);
+
+        let model = TaskbarModel::new(
+            &windows,
+            &Config::default(),
+            &Filter::default(),
+            &icon::Cache::default(),
+            &attention,
+            &decorations,
+            &Launchers::default(),
+        );
+        let button = model.iter().find(|button| button.id == 2).unwrap();
+
+        assert!(button.urgent);
+        assert_eq!(button.notifications, vec!["New message"]);
+        assert_eq!(button.badge.as_deref(), Some("3"));
+        assert!(button.classes.contains(&"urgent".to_string()));
+        assert!(button.classes.contains(&"building".to_string()));
+    }
+
+    fn attention_config() -> Config {
+        config(
+            r#"{
+                "attention": {
+                    "apps": {
+                        "org.mozilla.firefox": { "mode": "blink", "duration": 2 },
+                        "Slack": { "mode": "auto_clear", "duration": 5 }
+                    }
+                }
+            }"#,
+        )
+    }
+
+    #[test]
+    fn attention_sticks_by_default() {
+        let (windows, config) = (windows(), attention_config());
+        let start = Instant::now();
+        let mut attention = Attention::default();
+        attention.mark(5, start);
+
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(attention.expire(later, &windows, &config), None);
+        assert!(attention.get(5).is_some());
+        assert!(!attention.is_blinking(5));
+    }
+
+    #[test]
+    fn attention_blinks_for_its_duration() {
+        let (windows, config) = (windows(), attention_config());
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut attention = Attention::default();
+        attention.mark(2, start);
+
+        // The first half is "on", so the button stands out straight away.
+        assert_eq!(attention.expire(start, &windows, &config), Some(ms(500)));
+        assert!(!attention.is_blinking(2));
+        assert_eq!(attention.expire(ms(500), &windows, &config), Some(ms(1000)));
+        assert!(attention.is_blinking(2));
+        assert_eq!(
+            attention.expire(ms(1250), &windows, &config),
+            Some(ms(1500))
+        );
+        assert!(!attention.is_blinking(2));
+        assert_eq!(
+            attention.expire(ms(1999), &windows, &config),
+            Some(ms(2000))
+        );
+        assert!(attention.is_blinking(2));
+
+        // Once the duration is up, the window stays urgent without blinking.
+        assert_eq!(attention.expire(ms(2000), &windows, &config), None);
+        assert!(attention.get(2).is_some());
+        assert!(!attention.is_blinking(2));
+    }
+
+    #[test]
+    fn attention_auto_clears_after_its_duration() {
+        let (windows, config) = (windows(), attention_config());
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut attention = Attention::default();
+        attention.mark(4, start);
+        attention.mark(5, start);
+
+        assert_eq!(attention.expire(secs(4), &windows, &config), Some(secs(5)));
+        assert!(attention.get(4).is_some());
+
+        // Asking for attention again restarts the clock.
+        attention.mark(4, secs(4));
+        assert_eq!(attention.expire(secs(5), &windows, &config), Some(secs(9)));
+        assert!(attention.get(4).is_some());
+
+        assert_eq!(attention.expire(secs(9), &windows, &config), None);
+        assert!(attention.get(4).is_none());
+        assert!(attention.get(5).is_some());
+    }
+
+    #[test]
+    fn app_classes_are_valid_css() {
+        assert_eq!(app_class("foot"), "app-foot");
+        assert_eq!(app_class("org.Mozilla.Firefox"), "app-org-mozilla-firefox");
+        assert_eq!(app_class("steam_app_1234"), "app-steam_app_1234");
+        assert_eq!(app_class("Ünïcode app"), "app--n-code-app");
+    }
+
+    #[test]
+    fn hints_are_the_same_length() {
+        let keys = ['a', 's', 'd'];
+
+        assert_eq!(hints(&keys, 0), Vec::<String>::new());
+        assert_eq!(hints(&keys, 2), vec!["a", "s"]);
+        assert_eq!(hints(&keys, 3), vec!["a", "s", "d"]);
+        assert_eq!(hints(&keys, 4), vec!["aa", "as", "ad", "sa"]);
+        assert_eq!(hints(&keys, 10).len(), 10);
+        assert!(hints(&keys, 10).iter().all(|hint| hint.len() == 3));
+        assert_eq!(hints(&keys, 10)[9], "saa");
+    }
+
+    #[test]
+    fn hints_are_assigned_in_taskbar_order() {
+        let model = model(&windows(), &Config::default(), &Filter::default())
+            .with_hints(&['a', 's', 'd'], "");
+
+        let hints = model
+            .iter()
+            .map(|button| (button.id, button.hint.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hints,
+            vec![(1, "aa"), (4, "as"), (2, "ad"), (5, "sa"), (3, "ss")]
+        );
+        assert_eq!(model.hint_match("a"), HintMatch::Partial);
+        assert_eq!(model.hint_match("sa"), HintMatch::Window(5));
+        assert_eq!(model.hint_match("dd"), HintMatch::Partial);
+    }
+
+    #[test]
+    fn typed_keys_exclude_other_hints() {
+        let model = model(&windows(), &Config::default(), &Filter::default())
+            .with_hints(&['a', 's', 'd'], "s");
+
+        let hinted = model
+            .iter()
+            .filter(|button| button.hint.is_some())
+            .map(|button| button.id)
+            .collect::<Vec<_>>();
+        assert_eq!(hinted, vec![5, 3]);
+        assert!(classes(&model, 5).contains(&"hint"));
+        assert!(classes(&model, 1).contains(&"hint-excluded"));
+        assert_eq!(model.hint_match("ss"), HintMatch::Window(3));
+        assert_eq!(TaskbarModel::default().hint_match("a"), HintMatch::None);
+    }
+
+    // Returns the badge, classes, and progress on the given window, if it has any decorations.
+    fn decorated(
+        decorations: &Decorations,
+        id: u64,
+    ) -> Option<(Option<&str>, Vec<&str>, Option<f64>)> {
+        decorations.0.get(&id).map(|window| {
+            (
+                window.badge.as_ref().map(|badge| badge.value.as_str()),
+                window.classes.keys().map(String::as_str).collect(),
+                window.progress.as_ref().map(|progress| progress.value),
+            )
+        })
+    }
+
+    #[test]
+    fn decorations_are_applied() {
+        let mut decorations = Decorations::default();
+        decorations.apply(1, &Decoration::Badge(Some("3".to_string())), None);
+        decorations.apply(1, &Decoration::Class("building".to_string()), None);
+        decorations.apply(1, &Decoration::Class("done".to_string()), None);
+        decorations.apply(1, &Decoration::Progress(Some(1.5)), None);
+        decorations.apply(2, &Decoration::Progress(Some(0.25)), None);
+
+        assert_eq!(
+            decorated(&decorations, 1),
+            Some((Some("3"), vec!["building", "done"], Some(1.0)))
+        );
+        assert_eq!(decorated(&decorations, 2), Some((None, vec![], Some(0.25))));
+
+        decorations.apply(1, &Decoration::Badge(None), None);
+        decorations.apply(1, &Decoration::Progress(None), None);
+        assert_eq!(
+            decorated(&decorations, 1),
+            Some((None, vec!["building", "done"], None))
+        );
+
+        decorations.apply(1, &Decoration::Clear, None);
+        assert_eq!(decorated(&decorations, 1), None);
+        assert!(decorated(&decorations, 2).is_some());
+    }
+
+    #[test]
+    fn decorations_expire_independently() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut decorations = Decorations::default();
+        decorations.apply(1, &Decoration::Badge(Some("3".to_string())), Some(at(10)));
+        decorations.apply(1, &Decoration::Class("building".to_string()), Some(at(20)));
+        decorations.apply(1, &Decoration::Class("pinned".to_string()), None);
+        decorations.apply(2, &Decoration::Progress(Some(0.5)), Some(at(5)));
+
+        assert_eq!(decorations.expire(start), Some(at(5)));
+        assert!(decorated(&decorations, 2).is_some());
+
+        // Decorations expire as soon as their time comes.
+        assert_eq!(decorations.expire(at(5)), Some(at(10)));
+        assert_eq!(decorated(&decorations, 2), None);
+
+        assert_eq!(decorations.expire(at(15)), Some(at(20)));
+        assert_eq!(
+            decorated(&decorations, 1),
+            Some((None, vec!["building", "pinned"], None))
+        );
+
+        // Only the class without an expiry is left, so nothing else is due.
+        assert_eq!(decorations.expire(at(60)), None);
+        assert_eq!(
+            decorated(&decorations, 1),
+            Some((None, vec!["pinned"], None))
+        );
+    }
+
+    #[test]
+    fn reapplying_a_decoration_replaces_its_expiry() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut decorations = Decorations::default();
+        decorations.apply(1, &Decoration::Class("building".to_string()), Some(at(10)));
+        decorations.apply(1, &Decoration::Class("building".to_string()), None);
+
+        assert_eq!(decorations.expire(at(30)), None);
+        assert_eq!(
+            decorated(&decorations, 1),
+            Some((None, vec!["building"], None))
+        );
+    }
+
+    #[test]
+    fn decorations_are_removed_with_their_windows() {
+        let mut decorations = Decorations::default();
+        decorations.apply(1, &Decoration::Class("building".to_string()), None);
+        decorations.apply(99, &Decoration::Class("building".to_string()), None);
+
+        decorations.update(&windows());
+        assert!(decorated(&decorations, 1).is_some());
+        assert_eq!(decorated(&decorations, 99), None);
+    }
+
+    // Windows for matching notifications, which are all unfocused, with PIDs 1000 + ID.
+    fn notification_windows() -> Snapshot {
+        snapshot(
+            vec![
+                app_window(1, 10, 1, "org.telegram.desktop"),
+                app_window(2, 10, 2, "Slack"),
+                app_window(3, 10, 3, "com.slack.Slack"),
+                app_window(4, 10, 4, "slack"),
+                NiriWindow {
+                    is_focused: true,
+                    ..app_window(5, 10, 5, "foot")
+                },
+            ],
+            vec![workspace(10, 1, "DP-1")],
+        )
+    }
+
+    #[test]
+    fn notifications_match_the_process_tree() {
+        let windows = notification_windows();
+        let windows = windows.iter().collect::<Vec<_>>();
+        let config = Config::default();
+
+        // The sender is a child of the second window's process.
+        assert_eq!(
+            notification_targets(&windows, &[4242, 1002, 1], Some("foot"), &config),
+            vec![2]
+        );
+        // Focused windows don't need attention, so the desktop entry is used instead.
+        assert_eq!(
+            notification_targets(&windows, &[1005], Some("slack"), &config),
+            vec![4]
+        );
+        assert_eq!(
+            notification_targets(&windows, &[1005], None, &config),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn notifications_can_ignore_desktop_entries() {
+        let windows = notification_windows();
+        let windows = windows.iter().collect::<Vec<_>>();
+        let config = config(r#"{ "notifications": { "use_desktop_entry": false } }"#);
+
+        assert_eq!(
+            notification_targets(&windows, &[], Some("slack"), &config),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn desktop_entries_match_exactly_by_default() {
+        let windows = notification_windows();
+        let windows = windows.iter().collect::<Vec<_>>();
+        let config = Config::default();
+
+        assert_eq!(desktop_entry_targets(&windows, "slack", &config), vec![4]);
+        assert_eq!(desktop_entry_targets(&windows, "Slack", &config), vec![2]);
+        assert_eq!(
+            desktop_entry_targets(&windows, "telegram", &config),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn exact_desktop_entry_matches_win_over_fuzzy_ones() {
+        let windows = notification_windows();
+        let windows = windows.iter().collect::<Vec<_>>();
+        let config = config(r#"{ "notifications": { "use_fuzzy_matching": true } }"#);
+
+        assert_eq!(desktop_entry_targets(&windows, "slack", &config), vec![4]);
+        // Without an exact match, case-insensitive matches and matches on the last component of
+        // the app ID are all used.
+        assert_eq!(
+            desktop_entry_targets(&windows, "SLACK", &config),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            desktop_entry_targets(&windows, "org.example.Desktop", &config),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn desktop_entries_can_be_mapped_to_app_ids() {
+        let windows = notification_windows();
+        let windows = windows.iter().collect::<Vec<_>>();
+        let config = config(
+            r#"{ "notifications": { "map_app_ids": { "telegram": "org.telegram.desktop" } } }"#,
+        );
+
+        assert_eq!(
+            desktop_entry_targets(&windows, "telegram", &config),
+            vec![1]
+        );
+    }
+}
@@ -1,77 +1,325 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use futures::Stream;
-use niri_ipc::{Action, Event, Output, Reply, Request, Workspace, socket::Socket};
+use connection::Connection;
+pub use event_stream::{Event, EventStream};
+use niri_ipc::{Action, Output, Reply, Request, socket::SOCKET_PATH_ENV};
 pub use state::{Snapshot, Window};
-pub use window_stream::WindowStream;
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    record::{self, Record},
+};
 
+mod connection;
+mod event_stream;
 mod reply;
 mod state;
-mod window_stream;
+#[cfg(test)]
+pub mod testing;
 
 /// The top level client for Niri.
-#[derive(Debug, Clone, Copy)]
-pub struct Niri {}
+#[derive(Debug, Clone)]
+pub struct Niri {
+    socket: Option<Arc<Path>>,
+}
 
 impl Niri {
+    /// Creates a client for the Niri instance given by the `NIRI_SOCKET` environment variable.
     pub fn new() -> Self {
-        // Since niri_ipc is essentially stateless, we don't maintain anything much here.
-        Self {}
+        Self::with_socket(std::env::var_os(SOCKET_PATH_ENV).map(PathBuf::from))
+    }
+
+    /// Creates a client for the Niri instance listening on the given socket.
+    ///
+    /// If there's no socket, every request will fail. Each request opens a new connection, so we
+    /// don't maintain anything much here beyond the path.
+    pub fn with_socket(socket: Option<PathBuf>) -> Self {
+        Self {
+            socket: socket.map(Arc::from),
+        }
     }
 
     /// Requests that the given window ID should be activated.
     #[tracing::instrument(level = "TRACE", err)]
-    pub fn activate_window(&self, id: u64) -> Result<(), Error> {
-        let reply = request(Request::Action(Action::FocusWindow { id }))?;
+    pub async fn activate_window(&self, id: u64) -> Result<(), Error> {
+        let reply = self
+            .request(Request::Action(Action::FocusWindow { id }))
+            .await?;
         reply::typed!(Handled, reply)
     }
 
     /// Returns the current outputs.
-    pub fn outputs(&self) -> Result<HashMap<String, Output>, Error> {
-        let reply = request(Request::Outputs)?;
+    pub async fn outputs(&self) -> Result<HashMap<String, Output>, Error> {
+        let reply = self.request(Request::Outputs).await?;
         reply::typed!(Outputs, reply)
     }
 
-    /// Returns a stream of window snapshots.
-    pub fn window_stream(&self) -> WindowStream {
-        WindowStream::new()
+    /// Requests that a screenshot of the given window be written to the given absolute path.
+    ///
+    /// Note that Niri acknowledges the request before the file is written.
+    #[tracing::instrument(level = "TRACE", err)]
+    pub async fn screenshot_window(&self, id: u64, path: &Path) -> Result<(), Error> {
+        let reply = self
+            .request(Request::Action(Action::ScreenshotWindow {
+                id: Some(id),
+                write_to_disk: true,
+                path: Some(path.to_string_lossy().into_owned()),
+            }))
+            .await?;
+        reply::typed!(Handled, reply)
     }
 
-    /// Returns a stream of workspace changes.
-    pub fn workspace_stream(&self) -> Result<impl Stream<Item = Vec<Workspace>> + use<>, Error> {
-        let mut socket = socket()?;
-        let reply = socket.send(Request::EventStream).map_err(Error::NiriIpc)?;
-        reply::typed!(Handled, reply)?;
+    /// Opens a new connection to the Niri event stream.
+    ///
+    /// If recording is enabled, each event is recorded as it's received.
+    pub fn event_stream(&self) -> EventStream {
+        let connect = self.connect();
+
+        EventStream::new(async_stream::try_stream! {
+            let connection = connect.await?;
+            let reply = connection.send(Request::EventStream).await?;
+            reply::typed!(Handled, reply)?;
 
-        let mut next = socket.read_events();
-        Ok(async_stream::stream! {
             loop {
-                match next() {
-                    Ok(Event::WorkspacesChanged { workspaces }) => {
-                        yield workspaces;
-                    }
-                    Ok(_) => (),
-                    Err(e) => {
-                        tracing::error!(%e, "Niri IPC error reading from event stream");
-                    }
-                }
+                let event = connection.next_event().await?;
+                record::record(|| Record::Niri(event.clone()));
+                yield event;
             }
         })
     }
-}
 
-// Helper to marshal request errors into our own type system.
-//
-// This can't be used for event streams, since the stream callback is thrown away in this function.
-#[tracing::instrument(level = "TRACE", err)]
-fn request(request: Request) -> Result<Reply, Error> {
-    socket()?.send(request).map_err(Error::NiriIpc)
+    // Helper to send a single request on a new connection.
+    //
+    // This can't be used for event streams, since the connection is thrown away in this function.
+    #[tracing::instrument(level = "TRACE", err)]
+    async fn request(&self, request: Request) -> Result<Reply, Error> {
+        self.connect().await?.send(request).await
+    }
+
+    // Helper to connect to the Niri socket.
+    fn connect(&self) -> impl Future<Output = Result<Connection, Error>> + use<> {
+        let socket = self.socket.clone();
+        async move { Connection::connect(socket.as_deref().ok_or(Error::NiriSocketPath)?).await }
+    }
 }
 
-// Helper to connect to the Niri socket.
-#[tracing::instrument(level = "TRACE", err)]
-fn socket() -> Result<Socket, Error> {
-    Socket::connect().map_err(Error::NiriIpc)
+#[cfg(test)]
+mod tests {
+    use niri_ipc::{Event as NiriEvent, Window as NiriWindow};
+    use waybar_cffi::gtk::glib;
+
+    use crate::{
+        config::Config,
+        icon,
+        model::{Attention, Decorations, Launchers, TaskbarModel},
+        output::Filter,
+    };
+
+    use super::{
+        testing::{FakeNiri, window, workspace},
+        *,
+    };
+
+    // Runs the event stream from the given server until it ends, returning every event it
+    // produced.
+    fn collect(server: &FakeNiri) -> Vec<Event> {
+        glib::MainContext::new().block_on(async {
+            let stream = server.niri().event_stream();
+            let rx = stream.subscribe();
+            // The subscriber's channel closes once the stream ends and nothing else holds it.
+            drop(stream);
+
+            let mut events = Vec::new();
+            while let Ok(event) = rx.recv().await {
+                events.push(event);
+            }
+            events
+        })
+    }
+
+    fn snapshots(events: &[Event]) -> Vec<Vec<(u64, bool)>> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Windows(snapshot) => Some(
+                    snapshot
+                        .iter()
+                        .map(|window| (window.id, window.is_focused))
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn initial_state() -> Vec<NiriEvent> {
+        vec![
+            NiriEvent::WorkspacesChanged {
+                workspaces: vec![workspace(10, 1, "DP-1"), workspace(20, 2, "HDMI-A-1")],
+            },
+            NiriEvent::WindowsChanged {
+                windows: vec![
+                    window(1, 10, 1),
+                    NiriWindow {
+                        is_focused: true,
+                        ..window(2, 10, 2)
+                    },
+                    window(3, 20, 1),
+                ],
+            },
+        ]
+    }
+
+    #[test]
+    fn event_stream_replays_window_changes() {
+        let mut events = initial_state();
+        events.extend([
+            NiriEvent::WindowOpenedOrChanged {
+                window: window(4, 10, 3),
+            },
+            NiriEvent::WindowFocusChanged { id: Some(4) },
+            NiriEvent::WindowClosed { id: 1 },
+            // Nothing the taskbar cares about changes here, so there's no snapshot.
+            NiriEvent::WindowFocusChanged { id: Some(4) },
+        ]);
+        let server = FakeNiri::start(events);
+
+        assert_eq!(
+            snapshots(&collect(&server)),
+            vec![
+                vec![(1, false), (2, true), (3, false)],
+                vec![(1, false), (2, true), (4, false), (3, false)],
+                vec![(1, false), (2, false), (4, true), (3, false)],
+                vec![(2, false), (4, true), (3, false)],
+            ]
+        );
+    }
+
+    #[test]
+    fn event_stream_applies_events_deferred_until_windows_are_known() {
+        let server = FakeNiri::start(vec![
+            NiriEvent::WorkspacesChanged {
+                workspaces: vec![workspace(10, 1, "DP-1"), workspace(20, 2, "DP-1")],
+            },
+            NiriEvent::WorkspaceActivated {
+                id: 20,
+                focused: true,
+            },
+            NiriEvent::WindowsChanged {
+                windows: vec![window(1, 10, 1), window(2, 20, 1)],
+            },
+        ]);
+
+        let events = collect(&server);
+        assert_eq!(snapshots(&events), vec![vec![(1, false), (2, false)]]);
+
+        let Some(Event::Workspaces(workspaces)) = events
+            .iter()
+            .find(|event| matches!(event, Event::Workspaces(_)))
+        else {
+            panic!("no workspaces event in {events:?}");
+        };
+        assert!(!workspaces[0].is_active);
+        assert!(workspaces[1].is_active);
+        assert!(workspaces[1].is_focused);
+    }
+
+    #[test]
+    fn event_stream_reports_outputs_when_they_change() {
+        let mut events = initial_state();
+        events.extend([
+            NiriEvent::WorkspaceActivated {
+                id: 20,
+                focused: true,
+            },
+            NiriEvent::WorkspacesChanged {
+                workspaces: vec![workspace(10, 1, "DP-1")],
+            },
+        ]);
+        let server = FakeNiri::start(events);
+
+        let outputs: Vec<Vec<String>> = collect(&server)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Outputs(outputs) => Some(outputs.into_iter().collect()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            vec![
+                vec!["DP-1".to_string(), "HDMI-A-1".to_string()],
+                vec!["DP-1".to_string()]
+            ]
+        );
+    }
+
+    #[test]
+    fn event_stream_drives_taskbar_model() {
+        let mut events = initial_state();
+        events.push(NiriEvent::WindowOpenedOrChanged {
+            window: NiriWindow {
+                is_floating: true,
+                ..window(4, 20, 2)
+            },
+        });
+        let server = FakeNiri::start(events);
+
+        let snapshot = collect(&server)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Windows(snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .next_back()
+            .unwrap();
+        let config: Config = serde_jsonc::from_str(r#"{ "other_outputs": "separate" }"#).unwrap();
+        let model = TaskbarModel::new(
+            &snapshot,
+            &config,
+            &Filter::new(Some("DP-1".to_string()), true),
+            &icon::Cache::default(),
+            &Attention::default(),
+            &Decorations::default(),
+            &Launchers::default(),
+        );
+
+        let ids = |buttons: &[crate::model::ButtonModel]| {
+            buttons.iter().map(|button| button.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&model.buttons), vec![1, 2]);
+        assert!(model.buttons[1].classes.contains(&"focused".to_string()));
+        assert_eq!(model.other_outputs.len(), 1);
+        assert_eq!(model.other_outputs[0].output, "HDMI-A-1");
+        assert_eq!(ids(&model.other_outputs[0].buttons), vec![3, 4]);
+        assert!(
+            model.other_outputs[0].buttons[1]
+                .classes
+                .contains(&"floating".to_string())
+        );
+    }
+
+    #[test]
+    fn requests_are_sent_to_socket() {
+        let server = FakeNiri::start(Vec::new());
+
+        glib::MainContext::new()
+            .block_on(server.niri().activate_window(2))
+            .unwrap();
+
+        assert!(matches!(
+            server.requests().as_slice(),
+            [Request::Action(Action::FocusWindow { id: 2 })]
+        ));
+    }
+
+    #[test]
+    fn missing_socket_fails() {
+        let result = glib::MainContext::new().block_on(Niri::with_socket(None).activate_window(2));
+        assert!(matches!(result, Err(Error::NiriSocketPath)));
+    }
 }
@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use niri_ipc::{Event, Reply, Request};
+use serde::de::DeserializeOwned;
+use waybar_cffi::gtk::{
+    gio::{
+        DataInputStream, SocketClient, SocketConnection, UnixSocketAddress,
+        prelude::{DataInputStreamExtManual, IOStreamExt, OutputStreamExtManual, SocketClientExt},
+    },
+    glib,
+};
+
+use crate::error::Error;
+
+/// An asynchronous connection to the Niri IPC socket.
+///
+/// This speaks the same line based JSON protocol as [`niri_ipc::socket::Socket`], but all I/O goes
+/// through GIO, which means it's driven by the GLib main loop instead of blocking it.
+#[derive(Debug)]
+pub struct Connection {
+    connection: SocketConnection,
+    input: DataInputStream,
+}
+
+impl Connection {
+    /// Connects to the Niri socket at the given path.
+    pub async fn connect(path: &Path) -> Result<Self, Error> {
+        let connection = SocketClient::new()
+            .connect_future(&UnixSocketAddress::new(path))
+            .await
+            .map_err(Error::NiriSocket)?;
+        let input = DataInputStream::new(&connection.input_stream());
+
+        Ok(Self { connection, input })
+    }
+
+    /// Sends a request to Niri and waits for the reply.
+    pub async fn send(&self, request: Request) -> Result<Reply, Error> {
+        let mut buf = serde_json::to_string(&request).map_err(Error::NiriJson)?;
+        buf.push('\n');
+
+        match self
+            .connection
+            .output_stream()
+            .write_all_future(buf, glib::Priority::DEFAULT)
+            .await
+        {
+            Ok((_, _, None)) => {}
+            Ok((_, _, Some(e))) | Err((_, e)) => return Err(Error::NiriSocket(e)),
+        }
+
+        self.read().await
+    }
+
+    /// Waits for the next event.
+    ///
+    /// This is only useful after sending [`Request::EventStream`].
+    pub async fn next_event(&self) -> Result<Event, Error> {
+        self.read().await
+    }
+
+    async fn read<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let line = self
+            .input
+            .read_line_utf8_future(glib::Priority::DEFAULT)
+            .await
+            .map_err(Error::NiriSocket)?
+            .ok_or(Error::NiriDisconnected)?;
+
+        serde_json::from_str(&line).map_err(Error::NiriJson)
+    }
+}
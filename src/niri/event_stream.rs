@@ -0,0 +1,167 @@
+use std::{cell::RefCell, collections::BTreeSet, pin::pin, rc::Rc};
+
+use async_channel::{Receiver, Sender};
+use futures::{Stream, StreamExt};
+use niri_ipc::{Event as NiriEvent, Workspace};
+use waybar_cffi::gtk::glib;
+
+use crate::error::Error;
+
+use super::state::{Snapshot, WindowSet};
+
+/// A domain event derived from the Niri event stream.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The window set changed.
+    Windows(Snapshot),
+    /// The workspaces changed.
+    Workspaces(Vec<Workspace>),
+    /// The set of outputs with workspaces changed.
+    ///
+    /// Niri doesn't have events for outputs, but workspaces move between outputs when outputs come
+    /// and go, so this is as close as we can get.
+    Outputs(BTreeSet<String>),
+}
+
+/// A single stream of Niri events, shared between any number of subscribers.
+///
+/// Events are read asynchronously on the GLib main loop, so this must be created on the main
+/// thread.
+#[derive(Debug, Clone)]
+pub struct EventStream(Rc<RefCell<Shared>>);
+
+impl EventStream {
+    /// Creates a new event stream from raw Niri events.
+    ///
+    /// Use [`super::Niri::event_stream`] to get events from Niri itself.
+    pub fn new(events: impl Stream<Item = Result<NiriEvent, Error>> + 'static) -> Self {
+        let shared = Rc::new(RefCell::new(Shared::default()));
+
+        glib::spawn_future_local(event_stream(events, shared.clone()));
+
+        Self(shared)
+    }
+
+    /// Subscribes to events.
+    ///
+    /// The most recent event of each kind is sent to the new subscriber straight away, so
+    /// subscribers don't miss the initial state regardless of when they subscribe.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = async_channel::unbounded();
+
+        let mut shared = self.0.borrow_mut();
+        for event in shared.latest() {
+            // This can't fail, since we have the receiver and the channel is unbounded.
+            let _ = tx.try_send(event);
+        }
+        shared.subscribers.push(tx);
+
+        rx
+    }
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    subscribers: Vec<Sender<Event>>,
+    windows: Option<Snapshot>,
+    workspaces: Option<Vec<Workspace>>,
+    outputs: Option<BTreeSet<String>>,
+}
+
+impl Shared {
+    /// Broadcasts an event to all subscribers, dropping any that have gone away.
+    ///
+    /// Once every subscriber has gone away, there's no point reading any further events, so an
+    /// error is returned.
+    fn broadcast(&mut self, event: Event) -> Result<(), Error> {
+        match &event {
+            Event::Windows(snapshot) => self.windows = Some(snapshot.clone()),
+            Event::Workspaces(workspaces) => self.workspaces = Some(workspaces.clone()),
+            Event::Outputs(outputs) => self.outputs = Some(outputs.clone()),
+        }
+
+        if self.subscribers.is_empty() {
+            return Ok(());
+        }
+
+        self.subscribers
+            .retain(|tx| tx.try_send(event.clone()).is_ok());
+        if self.subscribers.is_empty() {
+            return Err(Error::EventStreamSend);
+        }
+
+        Ok(())
+    }
+
+    fn latest(&self) -> impl Iterator<Item = Event> + use<> {
+        // Windows go first, since the other events are most useful once there are windows to
+        // apply them to.
+        [
+            self.windows.clone().map(Event::Windows),
+            self.workspaces.clone().map(Event::Workspaces),
+            self.outputs.clone().map(Event::Outputs),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+async fn event_stream(
+    events: impl Stream<Item = Result<NiriEvent, Error>>,
+    shared: Rc<RefCell<Shared>>,
+) {
+    match read_events(events, shared).await {
+        Ok(()) => tracing::info!("Niri taskbar event stream ended"),
+        Err(e) => tracing::error!(%e, "Niri taskbar event stream error"),
+    }
+}
+
+async fn read_events(
+    events: impl Stream<Item = Result<NiriEvent, Error>>,
+    shared: Rc<RefCell<Shared>>,
+) -> Result<(), Error> {
+    let mut raw_events = pin!(events);
+    let mut state = WindowSet::new();
+    let mut outputs = None;
+
+    // If Niri goes away, there's not much point trying to reconnect: the compositor is gone, and
+    // Waybar is about to follow.
+    while let Some(event) = raw_events.next().await {
+        let event = event.inspect_err(|e| {
+            tracing::error!(%e, "Niri IPC error reading from event stream");
+        })?;
+
+        let mut events = Vec::new();
+        let workspaces_changed = matches!(
+            &event,
+            NiriEvent::WorkspacesChanged { .. } | NiriEvent::WorkspaceActivated { .. }
+        );
+
+        // The window set won't produce anything until it has both windows and workspaces, which
+        // means the window snapshot naturally comes before any workspace or output events.
+        if let Some(snapshot) = state.with_event(event) {
+            events.push(Event::Windows(snapshot));
+
+            if workspaces_changed || outputs.is_none() {
+                let workspaces = state.workspaces();
+                let new_outputs = workspaces
+                    .iter()
+                    .filter_map(|workspace| workspace.output.clone())
+                    .collect::<BTreeSet<_>>();
+
+                events.push(Event::Workspaces(workspaces));
+                if outputs.as_ref() != Some(&new_outputs) {
+                    outputs = Some(new_outputs.clone());
+                    events.push(Event::Outputs(new_outputs));
+                }
+            }
+        }
+
+        let mut shared = shared.borrow_mut();
+        for event in events.into_iter() {
+            shared.broadcast(event)?;
+        }
+    }
+
+    Ok(())
+}
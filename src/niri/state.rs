@@ -1,83 +1,149 @@
-use std::{collections::BTreeMap, fmt::Display, ops::Deref};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    ops::Deref,
+};
 
+use itertools::Itertools;
 use niri_ipc::{Event, Window as NiriWindow, WindowLayout, Workspace};
 
 /// The toplevel window set within Niri, updated via the Niri event stream.
 #[derive(Debug)]
-pub struct WindowSet(Option<Inner>);
+pub struct WindowSet {
+    inner: Option<Inner>,
+    /// Events that arrived before the state they apply to, which are applied once it arrives.
+    pending: VecDeque<Event>,
+}
+
+/// The most events that will be deferred while waiting for the initial state.
+///
+/// Niri sends the initial state before anything else, so this should never be reached, but if it
+/// is, the oldest events are dropped rather than holding onto events forever.
+const MAX_PENDING: usize = 256;
 
 impl WindowSet {
     /// Creates a new window set.
     pub fn new() -> Self {
-        Self(None)
+        Self {
+            inner: None,
+            pending: VecDeque::new(),
+        }
     }
 
     /// Updates the window set based on the given [`niri_ipc::Event`].
+    ///
+    /// A new snapshot is only returned if the event changed something that the taskbar cares
+    /// about.
     #[tracing::instrument(level = "TRACE", skip(self))]
     pub fn with_event(&mut self, event: Event) -> Option<Snapshot> {
+        let changed = self.apply(event);
+
+        match &self.inner {
+            Some(Inner::Ready(state)) if changed => {
+                state.check_invariants();
+                Some(state.snapshot())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the current workspaces, or an empty list if the window set isn't ready yet.
+    pub fn workspaces(&self) -> Vec<Workspace> {
+        if let Some(Inner::Ready(state)) = &self.inner {
+            state.workspaces.values().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Describes each way in which the window set is inconsistent, once it's ready.
+    #[cfg(test)]
+    pub fn invariant_violations(&self) -> Vec<String> {
+        match &self.inner {
+            Some(Inner::Ready(state)) => state.invariant_violations(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn apply(&mut self, event: Event) -> bool {
         // This is mildly annoying, because Niri actually has the same state within it and could
         // easily send it on each event, but we have to replicate Niri's own logic and hope we get
         // it right.
         match event {
-            Event::WindowsChanged { windows } => match self.0.take() {
-                Some(Inner::WorkspacesOnly(workspaces)) => {
-                    self.0 = Some(Inner::Ready(Niri::new(windows, workspaces)));
-                }
-                Some(Inner::WindowsOnly(_)) | None => {
-                    self.0 = Some(Inner::WindowsOnly(windows));
-                }
-                Some(Inner::Ready(mut state)) => {
-                    state.replace_windows(windows);
-                    self.0 = Some(Inner::Ready(state));
-                }
+            Event::WindowsChanged { windows } => self.replace_windows(windows),
+            Event::WorkspacesChanged { workspaces } => self.replace_workspaces(workspaces),
+            Event::WorkspaceActivated { id, focused } => match &mut self.inner {
+                Some(Inner::Ready(state)) => state.activate_workspace(id, focused),
+                _ => self.defer(Event::WorkspaceActivated { id, focused }),
             },
-            Event::WorkspacesChanged { workspaces } => match self.0.take() {
-                Some(Inner::WindowsOnly(windows)) => {
-                    self.0 = Some(Inner::Ready(Niri::new(windows, workspaces)));
-                }
-                Some(Inner::WorkspacesOnly(_)) | None => {
-                    self.0 = Some(Inner::WorkspacesOnly(workspaces));
-                }
-                Some(Inner::Ready(mut state)) => {
-                    state.replace_workspaces(workspaces);
-                    self.0 = Some(Inner::Ready(state));
-                }
+            event if is_window_event(&event) => match self.windows_mut() {
+                Some(windows) => windows.apply(event),
+                None => self.defer(event),
             },
-            Event::WindowClosed { id } => {
-                if let Some(Inner::Ready(state)) = &mut self.0 {
-                    state.remove_window(id);
-                } else {
-                    tracing::warn!(%self, "unexpected state for WindowClosed event");
-                }
-            }
-            Event::WindowOpenedOrChanged { window } => {
-                if let Some(Inner::Ready(state)) = &mut self.0 {
-                    state.upsert_window(window);
-                } else {
-                    tracing::warn!(%self, "unexpected state for WindowOpenedOrChanged event");
-                }
-            }
-            Event::WindowFocusChanged { id } => {
-                if let Some(Inner::Ready(state)) = &mut self.0 {
-                    state.set_focus(id);
-                } else {
-                    tracing::warn!(%self, "unexpected state for WindowFocusChanged event");
-                }
+            _ => false,
+        }
+    }
+
+    fn defer(&mut self, event: Event) -> bool {
+        tracing::debug!(%self, ?event, "deferring event until the initial state is known");
+        if self.pending.len() >= MAX_PENDING {
+            let dropped = self.pending.pop_front();
+            tracing::warn!(?dropped, "too many deferred events; dropping the oldest");
+        }
+        self.pending.push_back(event);
+        false
+    }
+
+    fn replace_windows(&mut self, windows: Vec<NiriWindow>) -> bool {
+        // Any window events that were waiting for the window list are older than it, so it
+        // already includes their changes. Replaying them would roll it back.
+        self.pending.retain(|event| !is_window_event(event));
+
+        let windows = Windows::new(windows);
+        self.inner = Some(match self.inner.take() {
+            Some(Inner::WorkspacesOnly(workspaces)) => Inner::Ready(Niri::new(windows, workspaces)),
+            Some(Inner::WindowsOnly(_)) | None => Inner::WindowsOnly(windows),
+            Some(Inner::Ready(mut state)) => {
+                state.windows = windows;
+                Inner::Ready(state)
             }
-            Event::WindowLayoutsChanged { changes } => {
-                if let Some(Inner::Ready(state)) = &mut self.0 {
-                    for (window_id, layout) in changes.into_iter() {
-                        state.update_window_layout(window_id, layout);
-                    }
-                }
+        });
+
+        self.replay();
+        true
+    }
+
+    fn replace_workspaces(&mut self, workspaces: Vec<Workspace>) -> bool {
+        // Similarly, any deferred activations are already reflected in the new workspaces.
+        self.pending
+            .retain(|event| !matches!(event, Event::WorkspaceActivated { .. }));
+
+        self.inner = Some(match self.inner.take() {
+            Some(Inner::WindowsOnly(windows)) => Inner::Ready(Niri::new(windows, workspaces)),
+            Some(Inner::WorkspacesOnly(_)) | None => Inner::WorkspacesOnly(workspaces),
+            Some(Inner::Ready(mut state)) => {
+                state.replace_workspaces(workspaces);
+                Inner::Ready(state)
             }
-            _ => {}
+        });
+
+        self.replay();
+        true
+    }
+
+    // Applies any deferred events that can now be applied, in the order they arrived. Any that
+    // still can't be applied are deferred again.
+    fn replay(&mut self) {
+        for event in std::mem::take(&mut self.pending) {
+            self.apply(event);
         }
+    }
 
-        if let Some(Inner::Ready(state)) = &self.0 {
-            Some(state.snapshot())
-        } else {
-            None
+    fn windows_mut(&mut self) -> Option<&mut Windows> {
+        match &mut self.inner {
+            Some(Inner::WindowsOnly(windows)) => Some(windows),
+            Some(Inner::Ready(state)) => Some(&mut state.windows),
+            Some(Inner::WorkspacesOnly(_)) | None => None,
         }
     }
 }
@@ -87,7 +153,7 @@ impl Display for WindowSet {
         write!(
             f,
             "{}",
-            match &self.0 {
+            match &self.inner {
                 Some(Inner::Ready(_)) => "ready",
                 Some(Inner::WindowsOnly(_)) => "windows only",
                 Some(Inner::WorkspacesOnly(_)) => "workspaces only",
@@ -97,15 +163,27 @@ impl Display for WindowSet {
     }
 }
 
+/// Checks if the event updates individual windows, rather than replacing the whole window list.
+fn is_window_event(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::WindowClosed { .. }
+            | Event::WindowOpenedOrChanged { .. }
+            | Event::WindowFocusChanged { .. }
+            | Event::WindowLayoutsChanged { .. }
+    )
+}
+
 /// The inner state machine as we establish a new event stream.
 ///
 /// Niri guarantees that we will get [`niri_ipc::Event::WindowsChanged`] and
 /// [`niri_ipc::Event::WorkspacesChanged`] events at the start of the stream before getting any
 /// update events, but not which order they'll come in, so we have to handle that as we build up
-/// the window set.
+/// the window set. Window events are applied as soon as we have windows, even if we don't have
+/// workspaces yet.
 #[derive(Debug)]
 enum Inner {
-    WindowsOnly(Vec<NiriWindow>),
+    WindowsOnly(Windows),
     WorkspacesOnly(Vec<Workspace>),
     Ready(Niri),
 }
@@ -113,62 +191,111 @@ enum Inner {
 /// The Niri state, as best as we can reconstruct it based on the event stream.
 #[derive(Debug)]
 struct Niri {
-    windows: BTreeMap<u64, NiriWindow>,
+    windows: Windows,
     workspaces: BTreeMap<u64, Workspace>,
 }
 
 impl Niri {
-    fn new(windows: Vec<NiriWindow>, workspaces: Vec<Workspace>) -> Self {
+    fn new(windows: Windows, workspaces: Vec<Workspace>) -> Self {
         let mut niri = Niri {
-            windows: Default::default(),
+            windows,
             workspaces: Default::default(),
         };
 
         niri.replace_workspaces(workspaces);
-        niri.replace_windows(windows);
 
         niri
     }
 
-    fn remove_window(&mut self, id: u64) {
-        self.windows.remove(&id);
-    }
+    fn activate_workspace(&mut self, id: u64, focused: bool) -> bool {
+        let Some(output) = self.workspaces.get(&id).map(|ws| ws.output.clone()) else {
+            tracing::warn!(id, "got activation for unknown workspace");
+            return false;
+        };
 
-    fn replace_windows(&mut self, windows: Vec<NiriWindow>) {
-        self.windows = windows
-            .into_iter()
-            .map(|window| (window.id, window))
-            .collect();
-    }
+        // Only one workspace can be active on each output, and only one can be focused overall.
+        let mut changed = false;
+        for workspace in self.workspaces.values_mut() {
+            if workspace.output == output && workspace.is_active != (workspace.id == id) {
+                workspace.is_active = workspace.id == id;
+                changed = true;
+            }
+            if focused && workspace.is_focused != (workspace.id == id) {
+                workspace.is_focused = workspace.id == id;
+                changed = true;
+            }
+        }
 
-    fn replace_workspaces(&mut self, workspaces: Vec<Workspace>) {
-        self.workspaces = workspaces.into_iter().map(|ws| (ws.id, ws)).collect();
+        changed
     }
 
-    fn set_focus(&mut self, id: Option<u64>) {
-        // We have to manually patch up the window is_focused values.
-        for window in self.windows.values_mut() {
-            window.is_focused = Some(window.id) == id;
+    /// Logs a warning if the reconstructed state is something Niri itself would never have.
+    ///
+    /// Since we're replicating Niri's logic, this is the best way we have of noticing when we've
+    /// got it wrong.
+    fn check_invariants(&self) {
+        for violation in self.invariant_violations() {
+            tracing::warn!(%violation, "reconstructed Niri state is inconsistent");
         }
     }
 
-    fn update_window_layout(&mut self, window_id: u64, layout: WindowLayout) {
-        if let Some(window) = self.windows.get_mut(&window_id) {
-            window.layout = layout;
-        } else {
-            tracing::warn!(window_id, ?layout, "got window layout for unknown window");
+    /// Describes each way in which the reconstructed state is inconsistent.
+    fn invariant_violations(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        let focused = self
+            .windows
+            .values()
+            .filter(|window| window.is_focused)
+            .map(|window| window.id)
+            .collect::<Vec<_>>();
+        if focused.len() > 1 {
+            violations.push(format!("more than one window is focused: {focused:?}"));
         }
-    }
 
-    fn upsert_window(&mut self, window: NiriWindow) {
-        // Ensure that we update other windows if the new window is focused.
-        if window.is_focused {
-            self.windows.values_mut().for_each(|window| {
-                window.is_focused = false;
-            })
+        for window in self.windows.values() {
+            if let Some(ws_id) = window.workspace_id {
+                if !self.workspaces.contains_key(&ws_id) {
+                    violations.push(format!(
+                        "window {} is on unknown workspace {ws_id}",
+                        window.id
+                    ));
+                }
+            }
         }
 
-        self.windows.insert(window.id, window);
+        let focused = self
+            .workspaces
+            .values()
+            .filter(|workspace| workspace.is_focused)
+            .map(|workspace| workspace.id)
+            .collect::<Vec<_>>();
+        if focused.len() > 1 {
+            violations.push(format!("more than one workspace is focused: {focused:?}"));
+        }
+
+        let active = self
+            .workspaces
+            .values()
+            .filter(|workspace| workspace.is_active)
+            .into_group_map_by(|workspace| workspace.output.as_deref());
+        for (output, workspaces) in active {
+            if workspaces.len() > 1 {
+                let ids = workspaces
+                    .iter()
+                    .map(|workspace| workspace.id)
+                    .collect_vec();
+                violations.push(format!(
+                    "more than one workspace is active on output {output:?}: {ids:?}"
+                ));
+            }
+        }
+
+        violations
+    }
+
+    fn replace_workspaces(&mut self, workspaces: Vec<Workspace>) {
+        self.workspaces = workspaces.into_iter().map(|ws| (ws.id, ws)).collect();
     }
 
     /// Create a snapshot of the current window state, ordered by workspace index.
@@ -209,24 +336,115 @@ impl Niri {
         wws.into_iter()
             .map(|ww| Window {
                 window: ww.window.clone(),
-                output: ww.workspace.output.clone(),
+                workspace: ww.workspace.clone(),
             })
             .collect()
     }
 }
 
+/// The windows we know about, keyed by ID.
+#[derive(Debug)]
+struct Windows(BTreeMap<u64, NiriWindow>);
+
+impl Windows {
+    fn new(windows: Vec<NiriWindow>) -> Self {
+        Self(
+            windows
+                .into_iter()
+                .map(|window| (window.id, window))
+                .collect(),
+        )
+    }
+
+    /// Applies a window event, returning whether anything changed.
+    fn apply(&mut self, event: Event) -> bool {
+        match event {
+            Event::WindowClosed { id } => self.remove_window(id),
+            Event::WindowOpenedOrChanged { window } => {
+                self.upsert_window(window);
+                true
+            }
+            Event::WindowFocusChanged { id } => self.set_focus(id),
+            Event::WindowLayoutsChanged { changes } => {
+                // Don't short circuit: every layout needs to be updated, even if an earlier one
+                // already changed.
+                changes
+                    .into_iter()
+                    .fold(false, |changed, (window_id, layout)| {
+                        self.update_window_layout(window_id, layout) || changed
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_window(&mut self, id: u64) -> bool {
+        self.0.remove(&id).is_some()
+    }
+
+    fn set_focus(&mut self, id: Option<u64>) -> bool {
+        // We have to manually patch up the window is_focused values.
+        let mut changed = false;
+        for window in self.0.values_mut() {
+            let is_focused = Some(window.id) == id;
+            changed |= window.is_focused != is_focused;
+            window.is_focused = is_focused;
+        }
+
+        changed
+    }
+
+    fn update_window_layout(&mut self, window_id: u64, layout: WindowLayout) -> bool {
+        if let Some(window) = self.0.get_mut(&window_id) {
+            // Niri sends layout changes on every frame while windows are being resized or moved,
+            // but the only part of the layout that the taskbar uses is the position within the
+            // scrolling layout, so there's no need for a new snapshot unless that changes.
+            let changed = window.layout.pos_in_scrolling_layout != layout.pos_in_scrolling_layout;
+            window.layout = layout;
+            changed
+        } else {
+            tracing::warn!(window_id, ?layout, "got window layout for unknown window");
+            false
+        }
+    }
+
+    fn upsert_window(&mut self, window: NiriWindow) {
+        // Ensure that we update other windows if the new window is focused.
+        if window.is_focused {
+            self.0.values_mut().for_each(|window| {
+                window.is_focused = false;
+            })
+        }
+
+        self.0.insert(window.id, window);
+    }
+}
+
+impl Deref for Windows {
+    type Target = BTreeMap<u64, NiriWindow>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 /// A snapshot of current toplevel windows, ordered by workspace index.
 pub type Snapshot = Vec<Window>;
 
 #[derive(Debug, Clone)]
 pub struct Window {
     window: NiriWindow,
-    output: Option<String>,
+    workspace: Workspace,
 }
 
 impl Window {
     pub fn output(&self) -> Option<&str> {
-        self.output.as_deref()
+        self.workspace.output.as_deref()
+    }
+
+    /// Returns the workspace the window is on.
+    pub fn workspace(&self) -> &Workspace {
+        &self.workspace
     }
 }
 
@@ -237,3 +455,470 @@ impl Deref for Window {
         &self.window
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Write as _, path::Path};
+
+    use crate::{
+        niri::testing::{layout, window, workspace},
+        record::{self, Record},
+    };
+
+    use super::*;
+
+    // Describes a snapshot with one line per window, in snapshot order.
+    fn describe(snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+        for window in snapshot {
+            let workspace = window.workspace();
+            let _ = write!(
+                out,
+                "window {} {:?} {:?} on workspace {} ({}, active: {}, focused: {}) at {:?}",
+                window.id,
+                window.app_id.as_deref().unwrap_or_default(),
+                window.title.as_deref().unwrap_or_default(),
+                workspace.idx,
+                workspace.output.as_deref().unwrap_or_default(),
+                workspace.is_active,
+                workspace.is_focused,
+                window.layout.pos_in_scrolling_layout,
+            );
+            if window.is_focused {
+                out.push_str(" focused");
+            }
+            if window.is_floating {
+                out.push_str(" floating");
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    // Replays a recording through a window set, describing each snapshot it produces along with
+    // the event that produced it.
+    fn replay(path: &Path) -> String {
+        let mut set = WindowSet::new();
+        let mut out = String::new();
+
+        for (i, entry) in record::load(path).unwrap().into_iter().enumerate() {
+            let Record::Niri(event) = entry.record else {
+                continue;
+            };
+            let name = serde_json::to_value(&event)
+                .ok()
+                .and_then(|value| value.as_object()?.keys().next().cloned())
+                .unwrap_or_default();
+
+            let snapshot = set.with_event(event);
+            let violations = set.invariant_violations();
+            assert!(
+                violations.is_empty(),
+                "{path:?} line {}: {violations:?}",
+                i + 1
+            );
+
+            if let Some(snapshot) = snapshot {
+                let _ = writeln!(out, "# line {}: {name}", i + 1);
+                out.push_str(&describe(&snapshot));
+            }
+        }
+
+        out
+    }
+
+    /// Checks each event log in `fixtures` against the snapshots expected from it.
+    ///
+    /// The logs in `fixtures/synthetic` were written by hand in the recording format, to cover
+    /// sequences that are awkward to reproduce on demand: they weren't captured from Niri, so their
+    /// timings, PIDs, and layouts are placeholders. Real captures go in `fixtures/recorded`: record
+    /// a session with `NIRI_TASKBAR_RECORD`, trim it to the events of interest, replace any
+    /// personal window titles, then set `UPDATE_FIXTURES` to generate its snapshots and check them
+    /// by hand.
+    ///
+    /// Set `UPDATE_FIXTURES` to rewrite the expected snapshots after an intentional change.
+    #[test]
+    fn event_fixtures() {
+        let base = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/niri/fixtures");
+        let update = std::env::var_os("UPDATE_FIXTURES").is_some();
+
+        let mut recordings = ["recorded", "synthetic"]
+            .into_iter()
+            .filter_map(|dir| std::fs::read_dir(base.join(dir)).ok())
+            .flatten()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+            .collect_vec();
+        recordings.sort();
+        assert!(!recordings.is_empty());
+
+        for recording in recordings {
+            let expected_path = recording.with_extension("snap");
+            let actual = replay(&recording);
+
+            if update {
+                std::fs::write(&expected_path, &actual).unwrap();
+            } else {
+                let expected = std::fs::read_to_string(&expected_path).unwrap();
+                assert_eq!(actual, expected, "{recording:?}");
+            }
+        }
+    }
+
+    fn ids(snapshot: Option<Snapshot>) -> Option<Vec<(u64, bool)>> {
+        snapshot.map(|snapshot| {
+            snapshot
+                .iter()
+                .map(|window| (window.id, window.is_focused))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn window_events_older_than_window_list_are_discarded() {
+        let mut set = WindowSet::new();
+        set.with_event(Event::WorkspacesChanged {
+            workspaces: vec![workspace(10, 1, "DP-1")],
+        });
+        set.with_event(Event::WindowClosed { id: 1 });
+        set.with_event(Event::WindowFocusChanged { id: Some(2) });
+
+        // The window list was sent after the window closed, so it no longer includes it, and the
+        // focus has since moved on.
+        let mut focused = window(3, 10, 2);
+        focused.is_focused = true;
+        let snapshot = set.with_event(Event::WindowsChanged {
+            windows: vec![window(2, 10, 1), focused],
+        });
+        assert_eq!(ids(snapshot), Some(vec![(2, false), (3, true)]));
+    }
+
+    #[test]
+    fn activations_older_than_workspaces_are_discarded() {
+        let mut set = WindowSet::new();
+        set.with_event(Event::WindowsChanged {
+            windows: vec![window(1, 10, 1)],
+        });
+        set.with_event(Event::WorkspaceActivated {
+            id: 20,
+            focused: true,
+        });
+
+        let mut active = workspace(10, 1, "DP-1");
+        active.is_active = true;
+        active.is_focused = true;
+        set.with_event(Event::WorkspacesChanged {
+            workspaces: vec![active, workspace(20, 2, "DP-1")],
+        });
+
+        let workspaces = set.workspaces();
+        assert!(workspaces[0].is_active && workspaces[0].is_focused);
+        assert!(!workspaces[1].is_active && !workspaces[1].is_focused);
+    }
+
+    #[test]
+    fn newer_events_are_replayed_once_ready() {
+        let mut set = WindowSet::new();
+        set.with_event(Event::WorkspacesChanged {
+            workspaces: vec![workspace(10, 1, "DP-1"), workspace(20, 2, "DP-1")],
+        });
+        // The window list hasn't arrived, so this activation is newer than the workspaces, and
+        // has to be applied on top of them.
+        set.with_event(Event::WorkspaceActivated {
+            id: 20,
+            focused: true,
+        });
+
+        set.with_event(Event::WindowsChanged {
+            windows: vec![window(1, 20, 1)],
+        });
+
+        let workspaces = set.workspaces();
+        assert!(!workspaces[0].is_active);
+        assert!(workspaces[1].is_active && workspaces[1].is_focused);
+    }
+
+    #[test]
+    fn pending_events_are_capped() {
+        let mut set = WindowSet::new();
+        for id in 0..(MAX_PENDING as u64 + 10) {
+            set.with_event(Event::WindowClosed { id });
+        }
+
+        assert_eq!(set.pending.len(), MAX_PENDING);
+        assert!(matches!(
+            set.pending.front(),
+            Some(Event::WindowClosed { id: 10 })
+        ));
+    }
+
+    /// A small, deterministic PRNG (xorshift64*), so that failures can be reproduced from the
+    /// seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Self {
+            // The state must never be zero.
+            Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn chance(&mut self, percent: u64) -> bool {
+            self.next() % 100 < percent
+        }
+
+        fn pick<'a, T>(&mut self, items: impl IntoIterator<Item = &'a T>) -> Option<&'a T> {
+            let items = items.into_iter().collect_vec();
+            (!items.is_empty()).then(|| items[self.below(items.len())])
+        }
+    }
+
+    static OUTPUTS: &[&str] = &["DP-1", "DP-2", "HDMI-A-1"];
+
+    /// A simple model of the state Niri itself holds, which emits the events Niri would send as
+    /// it changes.
+    struct Model {
+        windows: BTreeMap<u64, NiriWindow>,
+        workspaces: BTreeMap<u64, Workspace>,
+        next_id: u64,
+    }
+
+    impl Model {
+        fn new(rng: &mut Rng) -> Self {
+            let mut model = Self {
+                windows: BTreeMap::new(),
+                workspaces: BTreeMap::new(),
+                next_id: 1,
+            };
+
+            let focused_output = rng.below(OUTPUTS.len());
+            for (i, output) in OUTPUTS.iter().enumerate() {
+                for idx in 1..=(rng.below(3) as u8 + 1) {
+                    let id = model.id();
+                    let mut workspace = workspace(id, idx, output);
+                    workspace.is_active = idx == 1;
+                    workspace.is_focused = idx == 1 && i == focused_output;
+                    model.workspaces.insert(id, workspace);
+                }
+            }
+
+            for _ in 0..rng.below(6) {
+                model.open(rng);
+            }
+
+            model
+        }
+
+        fn id(&mut self) -> u64 {
+            self.next_id += 1;
+            self.next_id
+        }
+
+        fn initial_events(&self, rng: &mut Rng) -> Vec<Event> {
+            let mut events = vec![self.workspaces_changed(), self.windows_changed()];
+            // Niri sends both before anything else, but in no particular order.
+            if rng.chance(50) {
+                events.reverse();
+            }
+            events
+        }
+
+        fn windows_changed(&self) -> Event {
+            Event::WindowsChanged {
+                windows: self.windows.values().cloned().collect(),
+            }
+        }
+
+        fn workspaces_changed(&self) -> Event {
+            Event::WorkspacesChanged {
+                workspaces: self.workspaces.values().cloned().collect(),
+            }
+        }
+
+        fn focus(&mut self, id: Option<u64>) {
+            for window in self.windows.values_mut() {
+                window.is_focused = Some(window.id) == id;
+            }
+        }
+
+        fn open(&mut self, rng: &mut Rng) -> Event {
+            let ws_id = *rng.pick(self.workspaces.keys()).unwrap();
+            let id = self.id();
+            let mut window = window(id, ws_id, rng.below(4) + 1);
+            window.is_floating = rng.chance(20);
+            if window.is_floating {
+                window.layout = layout(None);
+            }
+            if rng.chance(50) {
+                self.focus(None);
+                window.is_focused = true;
+            }
+
+            self.windows.insert(id, window.clone());
+            Event::WindowOpenedOrChanged { window }
+        }
+
+        fn step(&mut self, rng: &mut Rng) -> Event {
+            let window_id = rng.pick(self.windows.keys()).copied();
+
+            match (rng.below(8), window_id) {
+                (0, _) | (1..=3, None) => self.open(rng),
+                (1, Some(id)) => {
+                    // Move the window to another workspace, which can be on another output.
+                    let ws_id = *rng.pick(self.workspaces.keys()).unwrap();
+                    let window = self.windows.get_mut(&id).unwrap();
+                    window.workspace_id = Some(ws_id);
+                    window.title = Some(format!("Moved {id}"));
+                    Event::WindowOpenedOrChanged {
+                        window: window.clone(),
+                    }
+                }
+                (2, Some(id)) => {
+                    self.windows.remove(&id);
+                    Event::WindowClosed { id }
+                }
+                (3, Some(id)) => {
+                    let id = rng.chance(80).then_some(id);
+                    self.focus(id);
+                    Event::WindowFocusChanged { id }
+                }
+                (4, _) => {
+                    let ids = self
+                        .windows
+                        .values()
+                        .filter(|window| !window.is_floating)
+                        .map(|window| window.id)
+                        .filter(|_| rng.chance(50))
+                        .collect_vec();
+                    let changes = ids
+                        .into_iter()
+                        .map(|id| {
+                            let layout = layout(Some((rng.below(4) + 1, rng.below(2) + 1)));
+                            self.windows.get_mut(&id).unwrap().layout = layout.clone();
+                            (id, layout)
+                        })
+                        .collect();
+                    Event::WindowLayoutsChanged { changes }
+                }
+                (5, _) => {
+                    let id = *rng.pick(self.workspaces.keys()).unwrap();
+                    let focused = rng.chance(50);
+                    let output = self.workspaces[&id].output.clone();
+                    for workspace in self.workspaces.values_mut() {
+                        if workspace.output == output {
+                            workspace.is_active = workspace.id == id;
+                        }
+                        if focused {
+                            workspace.is_focused = workspace.id == id;
+                        }
+                    }
+                    Event::WorkspaceActivated { id, focused }
+                }
+                (6, _) => {
+                    // Add a workspace, or remove one that's empty and inactive, the way Niri does
+                    // as windows come and go.
+                    let empty = self
+                        .workspaces
+                        .values()
+                        .filter(|workspace| !workspace.is_active)
+                        .filter(|workspace| {
+                            !self
+                                .windows
+                                .values()
+                                .any(|window| window.workspace_id == Some(workspace.id))
+                        })
+                        .map(|workspace| workspace.id)
+                        .collect_vec();
+                    match rng.pick(&empty) {
+                        Some(id) if rng.chance(50) => {
+                            self.workspaces.remove(id);
+                        }
+                        _ => {
+                            let output = OUTPUTS[rng.below(OUTPUTS.len())];
+                            let idx = self
+                                .workspaces
+                                .values()
+                                .filter(|workspace| workspace.output.as_deref() == Some(output))
+                                .map(|workspace| workspace.idx)
+                                .max()
+                                .unwrap_or_default()
+                                + 1;
+                            let id = self.id();
+                            self.workspaces.insert(id, workspace(id, idx, output));
+                        }
+                    }
+                    self.workspaces_changed()
+                }
+                _ => self.windows_changed(),
+            }
+        }
+    }
+
+    #[test]
+    fn random_event_sequences_keep_invariants() {
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let mut model = Model::new(&mut rng);
+            let mut set = WindowSet::new();
+            let mut latest = None;
+
+            let initial = model.initial_events(&mut rng);
+            let steps = (0..100).map(|_| model.step(&mut rng)).collect_vec();
+
+            for (i, event) in initial.into_iter().chain(steps).enumerate() {
+                let description = format!("{event:?}");
+                if let Some(snapshot) = set.with_event(event) {
+                    latest = Some(snapshot);
+                }
+
+                let violations = set.invariant_violations();
+                assert!(
+                    violations.is_empty(),
+                    "seed {seed}, event {i}: {violations:?} after {description}"
+                );
+            }
+
+            // The final state has to match Niri's, not just be consistent.
+            let snapshot = latest.unwrap_or_default();
+            let actual = snapshot
+                .iter()
+                .map(|window| (window.id, window.window.clone()))
+                .collect::<BTreeMap<_, _>>();
+            assert!(
+                actual.keys().eq(model.windows.keys()),
+                "seed {seed}: windows {:?}, expected {:?}",
+                actual.keys(),
+                model.windows.keys()
+            );
+            for (id, expected) in model.windows.iter() {
+                let window = &actual[id];
+                assert!(
+                    window.is_focused == expected.is_focused
+                        && window.workspace_id == expected.workspace_id
+                        && window.layout.pos_in_scrolling_layout
+                            == expected.layout.pos_in_scrolling_layout,
+                    "seed {seed}: window {window:?}, expected {expected:?}"
+                );
+            }
+            for window in snapshot.iter() {
+                let expected = &model.workspaces[&window.workspace_id.unwrap()];
+                assert!(
+                    window.workspace().is_active == expected.is_active
+                        && window.workspace().is_focused == expected.is_focused,
+                    "seed {seed}: workspace {:?}, expected {expected:?}",
+                    window.workspace()
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Helpers for building Niri state in tests.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+};
+
+use niri_ipc::{Event, Reply, Request, Response, Window as NiriWindow, WindowLayout, Workspace};
+use tempfile::TempDir;
+
+use super::{Niri, Snapshot, state::WindowSet};
+
+/// Returns a tiled window on the given workspace, in the given column.
+pub fn window(id: u64, workspace_id: u64, column: usize) -> NiriWindow {
+    NiriWindow {
+        id,
+        title: Some(format!("Window {id}")),
+        app_id: Some(format!("app{id}")),
+        pid: Some(1000 + id as i32),
+        workspace_id: Some(workspace_id),
+        is_focused: false,
+        is_floating: false,
+        is_urgent: false,
+        layout: layout(Some((column, 1))),
+        focus_timestamp: None,
+    }
+}
+
+/// Returns a layout at the given position in the scrolling layout, if any.
+pub fn layout(pos_in_scrolling_layout: Option<(usize, usize)>) -> WindowLayout {
+    WindowLayout {
+        pos_in_scrolling_layout,
+        tile_size: (100.0, 100.0),
+        window_size: (100, 100),
+        tile_pos_in_workspace_view: None,
+        window_offset_in_tile: (0.0, 0.0),
+    }
+}
+
+/// Returns an inactive workspace on the given output.
+pub fn workspace(id: u64, idx: u8, output: &str) -> Workspace {
+    Workspace {
+        id,
+        idx,
+        name: None,
+        output: Some(output.to_string()),
+        is_urgent: false,
+        is_active: false,
+        is_focused: false,
+        active_window_id: None,
+    }
+}
+
+/// Builds a snapshot the same way the event stream does, from the initial windows and workspaces.
+pub fn snapshot(windows: Vec<NiriWindow>, workspaces: Vec<Workspace>) -> Snapshot {
+    let mut set = WindowSet::new();
+    set.with_event(Event::WorkspacesChanged { workspaces });
+    set.with_event(Event::WindowsChanged { windows })
+        .expect("snapshot once windows and workspaces are known")
+}
+
+/// A fake Niri IPC server, listening on a socket in a temporary directory.
+///
+/// Event stream requests are acknowledged and then sent the scripted events, after which the
+/// connection is closed, the same as Niri going away. Any other request is recorded and gets a
+/// `Handled` reply.
+#[derive(Debug)]
+pub struct FakeNiri {
+    dir: TempDir,
+    requests: Arc<Mutex<Vec<Request>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeNiri {
+    /// Starts a server that replays the given events to each event stream.
+    pub fn start(events: Vec<Event>) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(dir.path().join("niri.sock")).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::spawn({
+            let requests = requests.clone();
+            let stop = stop.clone();
+            move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    serve(stream.unwrap(), &events, &requests);
+                }
+            }
+        });
+
+        Self {
+            dir,
+            requests,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns the socket path, which is what `NIRI_SOCKET` would be set to.
+    pub fn socket(&self) -> PathBuf {
+        self.dir.path().join("niri.sock")
+    }
+
+    /// Returns a client connected to this server.
+    pub fn niri(&self) -> Niri {
+        Niri::with_socket(Some(self.socket()))
+    }
+
+    /// Returns every request other than event stream requests, in the order they were received.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeNiri {
+    fn drop(&mut self) {
+        // Wake the listener up so it notices it should stop.
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(self.socket());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(stream: UnixStream, events: &[Event], requests: &Mutex<Vec<Request>>) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap() == 0 {
+        return;
+    }
+
+    let mut writer = &stream;
+    let mut send = |value: String| {
+        // The client may already have gone away, which is fine.
+        let _ = writeln!(writer, "{value}");
+    };
+
+    let reply: Reply = Ok(Response::Handled);
+    match serde_json::from_str(&line).unwrap() {
+        Request::EventStream => {
+            send(serde_json::to_string(&reply).unwrap());
+            for event in events {
+                send(serde_json::to_string(event).unwrap());
+            }
+        }
+        request => {
+            requests.lock().unwrap().push(request);
+            send(serde_json::to_string(&reply).unwrap());
+        }
+    }
+}
@@ -4,7 +4,7 @@ use async_channel::Sender;
 use cache::ConnectionCache;
 use futures::{Stream, TryStreamExt};
 use itertools::Itertools;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use waybar_cffi::gtk::glib::{self};
 use zbus::{
     Connection, MatchRule, Message, MessageStream,
@@ -15,12 +15,13 @@ use zbus::{
 
 mod cache;
 
-/// Starts a stream of notifications.
+/// Starts a stream of notifications and launcher entry updates.
 ///
 /// Under the hood, this sets up a monitor on the D-Bus session bus and grabs
 /// any method call to the `Notify` method on the
-/// `org.freedesktop.Notifications` interface.
-pub fn stream() -> impl Stream<Item = EnrichedNotification> {
+/// `org.freedesktop.Notifications` interface, along with any `Update` signal
+/// on the `com.canonical.Unity.LauncherEntry` interface.
+pub fn stream() -> impl Stream<Item = Event> {
     // For lifetime reasons, it's easier to have an async channel extract the
     // data out of the GLib event loop than it is to return the stream directly.
     let (tx, rx) = async_channel::unbounded();
@@ -32,12 +33,19 @@ pub fn stream() -> impl Stream<Item = EnrichedNotification> {
     });
 
     async_stream::stream! {
-        while let Ok(notification) = rx.recv().await {
-            yield notification;
+        while let Ok(event) = rx.recv().await {
+            yield event;
         }
     }
 }
 
+/// Something an application told the desktop that the taskbar cares about.
+#[derive(Debug, Clone)]
+pub enum Event {
+    LauncherEntry(LauncherEntryUpdate),
+    Notification(EnrichedNotification),
+}
+
 /// A FDO notification with the PID of the connection that sent it, if
 /// available.
 #[derive(Debug, Clone)]
@@ -62,6 +70,53 @@ impl EnrichedNotification {
     }
 }
 
+/// The parts of a notification that the taskbar uses, in a form that can be recorded and replayed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedNotification {
+    app_name: Option<String>,
+    body: Option<String>,
+    desktop_entry: Option<String>,
+    pid: Option<u32>,
+    sender_pid: Option<i64>,
+    summary: String,
+}
+
+impl From<&EnrichedNotification> for RecordedNotification {
+    fn from(enriched: &EnrichedNotification) -> Self {
+        let notification = &enriched.notification;
+
+        Self {
+            app_name: (*notification.app_name).clone(),
+            body: (*notification.body).clone(),
+            desktop_entry: notification.hints.desktop_entry.clone(),
+            pid: enriched.pid,
+            sender_pid: notification.hints.sender_pid,
+            summary: notification.summary.clone(),
+        }
+    }
+}
+
+impl From<RecordedNotification> for EnrichedNotification {
+    fn from(recorded: RecordedNotification) -> Self {
+        Self {
+            notification: Notification {
+                app_name: recorded.app_name.into(),
+                replaces_id: None.into(),
+                app_icon: None.into(),
+                summary: recorded.summary,
+                body: recorded.body.into(),
+                actions: Actions(Vec::new()),
+                hints: Hints {
+                    desktop_entry: recorded.desktop_entry,
+                    sender_pid: recorded.sender_pid,
+                },
+                expire_timeout: -1,
+            },
+            pid: recorded.pid,
+        }
+    }
+}
+
 /// A FDO notification.
 //
 // We're parsing out more than we need here, but I'm hoping this'll be useful
@@ -133,21 +188,79 @@ pub struct Hints {
     // pub y: Option<i32>,
 }
 
+/// An update to the count and progress an application wants shown on its
+/// launcher, sent through the Unity launcher API that Firefox, Thunderbird,
+/// Telegram, and friends use.
+///
+/// Updates only include the properties that changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LauncherEntryUpdate {
+    /// The desktop entry of the application, without the `.desktop` suffix.
+    pub desktop_entry: String,
+    pub count: Option<i64>,
+    pub count_visible: Option<bool>,
+    pub progress: Option<f64>,
+    pub progress_visible: Option<bool>,
+}
+
+impl LauncherEntryUpdate {
+    /// Builds an update from the application URI and properties in an
+    /// `Update` signal, which should be of the form
+    /// `application://foo.desktop`.
+    fn new(app_uri: &str, properties: LauncherProperties) -> Option<Self> {
+        let desktop_entry = app_uri.strip_prefix("application://")?;
+        let desktop_entry = desktop_entry
+            .strip_suffix(".desktop")
+            .unwrap_or(desktop_entry);
+        if desktop_entry.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            desktop_entry: desktop_entry.to_string(),
+            count: properties.count,
+            count_visible: properties.count_visible,
+            progress: properties.progress,
+            progress_visible: properties.progress_visible,
+        })
+    }
+}
+
+#[derive(Debug, Clone, DeserializeDict, Type)]
+#[zvariant(rename_all = "kebab-case", signature = "a{sv}")]
+struct LauncherProperties {
+    count: Option<i64>,
+    count_visible: Option<bool>,
+    progress: Option<f64>,
+    progress_visible: Option<bool>,
+    // urgent: Option<bool>,
+}
+
 static INTERFACE: &str = "org.freedesktop.Notifications";
 static METHOD: &str = "Notify";
 
+static LAUNCHER_INTERFACE: &str = "com.canonical.Unity.LauncherEntry";
+static LAUNCHER_SIGNAL: &str = "Update";
+
 #[tracing::instrument(level = "TRACE", skip_all, err)]
-async fn monitor_dbus(tx: Sender<EnrichedNotification>) -> anyhow::Result<()> {
+async fn monitor_dbus(tx: Sender<Event>) -> anyhow::Result<()> {
     let cache = cache::ConnectionCache::new(Duration::from_secs(86400));
 
     let conn = Connection::session().await?;
     let proxy = MonitoringProxy::new(&conn).await?;
     proxy
         .become_monitor(
-            &[MatchRule::builder()
-                .interface(INTERFACE)?
-                .member(METHOD)?
-                .build()],
+            &[
+                MatchRule::builder()
+                    .interface(INTERFACE)?
+                    .member(METHOD)?
+                    .build(),
+                MatchRule::builder()
+                    .msg_type(zbus::message::Type::Signal)
+                    .interface(LAUNCHER_INTERFACE)?
+                    .member(LAUNCHER_SIGNAL)?
+                    .build(),
+            ],
             0,
         )
         .await?;
@@ -155,7 +268,7 @@ async fn monitor_dbus(tx: Sender<EnrichedNotification>) -> anyhow::Result<()> {
     let mut stream = MessageStream::from(conn);
     while let Some(msg) = stream.try_next().await? {
         if let Err(e) = process_message(&tx, &cache, &msg).await {
-            tracing::error!(%e, ?msg, "error processing notification message");
+            tracing::error!(%e, ?msg, "error processing monitored message");
         }
     }
 
@@ -163,28 +276,40 @@ async fn monitor_dbus(tx: Sender<EnrichedNotification>) -> anyhow::Result<()> {
 }
 
 async fn process_message(
-    tx: &Sender<EnrichedNotification>,
+    tx: &Sender<Event>,
     cache: &ConnectionCache,
     msg: &Message,
 ) -> anyhow::Result<()> {
-    if msg.header().interface() == Some(&InterfaceName::from_static_str(INTERFACE)?)
-        && msg.header().member() == Some(&MemberName::from_static_str(METHOD)?)
+    let header = msg.header();
+    let interface = header.interface();
+    let member = header.member();
+
+    if interface == Some(&InterfaceName::from_static_str(LAUNCHER_INTERFACE)?)
+        && member == Some(&MemberName::from_static_str(LAUNCHER_SIGNAL)?)
+    {
+        let (app_uri, properties): (String, LauncherProperties) = msg.body().deserialize()?;
+        match LauncherEntryUpdate::new(&app_uri, properties) {
+            Some(update) => tx.send(Event::LauncherEntry(update)).await?,
+            None => tracing::debug!(app_uri, "ignoring launcher entry update for unknown URI"),
+        }
+    } else if interface == Some(&InterfaceName::from_static_str(INTERFACE)?)
+        && member == Some(&MemberName::from_static_str(METHOD)?)
     {
         // Pull the PID out of the connection cache, if we can.
         //
         // This isn't always useful: anything in a Flatpak is going to use
         // the portal's connection, which won't map to a toplevel, but it's
         // better than nothing.
-        let pid = if let Some(sender) = msg.header().sender() {
+        let pid = if let Some(sender) = header.sender() {
             cache.get(sender).await
         } else {
             None
         };
 
-        tx.send(EnrichedNotification {
+        tx.send(Event::Notification(EnrichedNotification {
             notification: msg.body().deserialize()?,
             pid,
-        })
+        }))
         .await?;
     }
 
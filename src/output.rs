@@ -1,122 +1,530 @@
-use niri_ipc::{LogicalOutput, Output};
-use waybar_cffi::gtk::gdk::{Monitor, traits::MonitorExt};
+use async_channel::{Receiver, Sender};
+use niri_ipc::{LogicalOutput, Output, Transform};
+use waybar_cffi::gtk::{
+    gdk::{self, Display, Monitor, traits::MonitorExt},
+    glib::{
+        GString,
+        translate::{ToGlibPtr, from_glib_full},
+    },
+};
+
+/// The environment variable that can be used to set the output the taskbar is on.
+pub static OUTPUT_ENV: &str = "NIRI_TASKBAR_OUTPUT";
 
 /// A filter to check if we should include a window button.
-#[derive(Debug, Clone)]
-pub enum Filter {
-    ShowAll,
-    Only(String),
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    local: Option<String>,
+    show_all: bool,
 }
 
 impl Filter {
+    /// Creates a filter for a taskbar on the given output, if known.
+    pub fn new(local: Option<String>, show_all: bool) -> Self {
+        Self { local, show_all }
+    }
+
+    /// Returns the output the taskbar is on, if known.
+    pub fn local(&self) -> Option<&str> {
+        self.local.as_deref()
+    }
+
     /// Checks if toplevels on this output should be shown.
     pub fn should_show(&self, output: &str) -> bool {
-        match self {
-            Self::ShowAll => true,
-            Self::Only(only) => only == output,
+        self.show_all || self.is_local(output)
+    }
+
+    /// Checks if this output is the one the taskbar is on.
+    ///
+    /// If we don't know which output the taskbar is on, every output is considered local.
+    pub fn is_local(&self, output: &str) -> bool {
+        self.local.as_deref().is_none_or(|local| local == output)
+    }
+}
+
+/// Returns a channel that receives a message whenever a monitor is added to or removed from the
+/// display, or an existing monitor changes position, size, or scale.
+pub fn monitor_changes(display: &Display) -> Receiver<()> {
+    let (tx, rx) = async_channel::unbounded();
+
+    for monitor in (0..display.n_monitors()).filter_map(|i| display.monitor(i)) {
+        watch_monitor(&monitor, tx.clone());
+    }
+
+    display.connect_monitor_added({
+        let tx = tx.clone();
+        move |_, monitor| {
+            tracing::debug!(?monitor, "monitor added");
+            watch_monitor(monitor, tx.clone());
+            let _ = tx.try_send(());
+        }
+    });
+
+    display.connect_monitor_removed(move |_, monitor| {
+        tracing::debug!(?monitor, "monitor removed");
+        let _ = tx.try_send(());
+    });
+
+    rx
+}
+
+fn watch_monitor(monitor: &Monitor, tx: Sender<()>) {
+    let geometry_tx = tx.clone();
+    monitor.connect_geometry_notify(move |monitor| {
+        tracing::debug!(?monitor, "monitor geometry changed");
+        let _ = geometry_tx.try_send(());
+    });
+
+    monitor.connect_scale_factor_notify(move |monitor| {
+        tracing::debug!(?monitor, "monitor scale changed");
+        let _ = tx.try_send(());
+    });
+}
+
+/// Returns the connector name of the given monitor (for example, `DP-1`), if Gdk knows it.
+///
+/// Gdk 3 doesn't expose this on the monitor itself, but the deprecated per-screen API does, and
+/// the Wayland backend fills it in from the output name advertised by the compositor, which is
+/// exactly what Niri names its outputs by. Older compositors and backends won't provide it.
+pub fn connector(monitor: &Monitor) -> Option<String> {
+    let display = monitor.display()?;
+    let index =
+        (0..display.n_monitors()).find(|i| display.monitor(*i).as_ref() == Some(monitor))?;
+    let screen = display.default_screen();
+
+    // SAFETY: gdk 0.18 has no safe binding for this function, so we call it directly.
+    //
+    // - `screen` holds a strong reference to the default screen for the rest of this function, and
+    //   the pointer from `to_glib_none` is borrowed from it, so it stays valid for the whole call.
+    // - `index` comes from `0..n_monitors()` on the screen's display, and Gdk 3 implements the
+    //   per-screen monitor API on top of the display's monitor list, so it's in range. Even if a
+    //   monitor were removed in between, Gdk checks the index and returns NULL rather than reading
+    //   out of bounds.
+    // - The return value is nullable and annotated `transfer full`: it's a newly allocated string
+    //   that we own. `from_glib_full` maps NULL to `None`, and otherwise takes ownership so that
+    //   `GString` frees it with `g_free` when it's dropped.
+    let name: Option<GString> = unsafe {
+        from_glib_full(gdk::ffi::gdk_screen_get_monitor_plug_name(
+            screen.to_glib_none().0,
+            index,
+        ))
+    };
+
+    name.map(String::from)
+}
+
+/// The properties of a Gdk monitor that can be compared against Niri outputs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitorInfo {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub scale_factor: i32,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+}
+
+impl MonitorInfo {
+    pub fn from_gdk(monitor: &Monitor) -> Self {
+        let geometry = monitor.geometry();
+
+        Self {
+            x: geometry.x(),
+            y: geometry.y(),
+            width: geometry.width(),
+            height: geometry.height(),
+            scale_factor: monitor.scale_factor(),
+            manufacturer: monitor.manufacturer().map(String::from),
+            model: monitor.model().map(String::from),
+        }
+    }
+}
+
+/// Returns the name of the Niri output that best matches the given monitor.
+///
+/// Each output is scored on how many of its properties match the monitor, and the output with the
+/// highest score wins. If more than one output shares the highest score, or no output matched on
+/// anything that actually distinguishes outputs from each other, then there's no match.
+pub fn best_match<'a>(
+    monitor: &MonitorInfo,
+    outputs: impl IntoIterator<Item = (&'a String, &'a Output)>,
+) -> Option<&'a str> {
+    let scored: Vec<_> = outputs
+        .into_iter()
+        .map(|(name, output)| (name, Score::new(monitor, output)))
+        .inspect(|(name, score)| {
+            tracing::debug!(name = name.as_str(), ?score, "scored Niri output");
+        })
+        .filter(|(_, score)| score.intersects(Score::DISTINCT))
+        .collect();
+
+    let best = scored.iter().map(|(_, score)| score.bits()).max()?;
+    let mut candidates = scored.iter().filter(|(_, score)| score.bits() == best);
+
+    match (candidates.next(), candidates.next()) {
+        (Some((name, _)), None) => Some(name.as_str()),
+        _ => {
+            tracing::warn!(
+                ?monitor,
+                "more than one Niri output matched the Gdk monitor equally"
+            );
+            None
         }
     }
 }
 
 bitflags::bitflags! {
-    /// A simple matcher to try to figure out if a Gdk 3 monitor and a Niri output are referring to
-    /// the same output.
+    /// The properties that matched between a Gdk monitor and a Niri output.
+    ///
+    /// The flag values double as weights: a higher flag is a stronger signal than all the lower
+    /// flags combined, so comparing the raw bits ranks candidates.
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    pub struct Matcher: u8 {
-        const GEOMETRY = 1 << 0;
-        const MODEL = 1 << 1;
-        const MANUFACTURER = 1 << 2;
+    struct Score: u8 {
+        const MODEL = 1 << 0;
+        const MANUFACTURER = 1 << 1;
+        const GEOMETRY = 1 << 2;
+        const POSITION = 1 << 3;
+
+        /// Properties that can tell otherwise identical monitors apart.
+        ///
+        /// Niri also reports serial numbers, but Gdk 3 doesn't expose them, so they can't be
+        /// compared.
+        const DISTINCT = Self::GEOMETRY.bits() | Self::POSITION.bits();
     }
 }
 
-impl Matcher {
-    pub fn new(monitor: &Monitor, output: &Output) -> Self {
+impl Score {
+    fn new(monitor: &MonitorInfo, output: &Output) -> Self {
         let Some(logical) = &output.logical else {
             tracing::info!(name = output.name, "output does not have a logical output");
             return Self::empty();
         };
 
-        let mut matches = Self::empty();
+        let mut score = Self::empty();
 
-        matches.set(
-            Matcher::GEOMETRY,
-            Geometry::from_gdk_monitor(monitor) == Geometry::from_niri_output(logical),
+        // Positions are in the global compositor space, which is in logical pixels for both Gdk
+        // and Niri, so these should be exactly equal.
+        score.set(
+            Self::POSITION,
+            monitor.x == logical.x && monitor.y == logical.y,
         );
 
-        matches.set(
-            Matcher::MODEL,
-            match (monitor.model(), &output.model) {
-                (Some(gdk_model), niri_model) => gdk_model.as_str() == niri_model,
-                (None, niri_model) if niri_model.is_empty() => true,
-                _ => false,
-            },
+        score.set(
+            Self::GEOMETRY,
+            logical_size_matches(monitor, logical) || physical_size_matches(monitor, output),
         );
 
-        matches.set(
-            Matcher::MANUFACTURER,
-            match (monitor.manufacturer(), &output.make) {
-                (Some(gdk_manufacturer), niri_make) => gdk_manufacturer.as_str() == niri_make,
-                (None, niri_make) if niri_make.is_empty() => true,
-                _ => false,
-            },
+        score.set(
+            Self::MODEL,
+            names_match(monitor.model.as_deref(), &output.model),
+        );
+
+        score.set(
+            Self::MANUFACTURER,
+            names_match(monitor.manufacturer.as_deref(), &output.make),
         );
 
-        matches
+        score
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Geometry {
-    width: i32,
-    height: i32,
-    x: i32,
-    y: i32,
+fn names_match(gdk: Option<&str>, niri: &str) -> bool {
+    match gdk {
+        Some(gdk) => gdk == niri,
+        None => niri.is_empty(),
+    }
 }
 
-impl Geometry {
-    fn from_gdk_monitor(monitor: &Monitor) -> Self {
-        let geometry = monitor.geometry();
-        let scale = monitor.scale_factor();
+// Gdk is apparently... uh, special when it comes to calculating the width and height of the
+// monitor, so we'll define sizes as "close enough is good enough".
+fn close_enough(a: f64, b: f64) -> bool {
+    b > 0.0 && ((a / b) - 1.0).abs() < 0.03
+}
 
-        Self {
-            width: geometry.width() * scale,
-            height: geometry.height() * scale,
-            x: geometry.x() * scale,
-            y: geometry.y() * scale,
+/// Checks the monitor size against the logical output size.
+///
+/// This works when Gdk gets the logical size from xdg-output, which is the case for any recent
+/// compositor, and handles fractional scales and transforms, since Niri's logical size already
+/// takes both into account.
+fn logical_size_matches(monitor: &MonitorInfo, logical: &LogicalOutput) -> bool {
+    close_enough(monitor.width.into(), logical.width.into())
+        && close_enough(monitor.height.into(), logical.height.into())
+}
+
+/// Checks the monitor size against the physical size of the output's current mode.
+///
+/// Without xdg-output, Gdk derives the monitor size by dividing the mode size by the integer scale
+/// factor, so multiplying it back out gets us (roughly) to the mode size. Modes are specified
+/// before any transform is applied, so rotated outputs need their dimensions swapped.
+fn physical_size_matches(monitor: &MonitorInfo, output: &Output) -> bool {
+    let Some(mode) = output.current_mode.and_then(|idx| output.modes.get(idx)) else {
+        return false;
+    };
+    let Some(logical) = &output.logical else {
+        return false;
+    };
+
+    let (width, height) = match logical.transform {
+        Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+            (mode.height, mode.width)
         }
-    }
+        _ => (mode.width, mode.height),
+    };
 
-    fn from_niri_output(logical: &LogicalOutput) -> Self {
-        let LogicalOutput {
-            width,
-            height,
-            scale,
+    let scale = monitor.scale_factor;
+    close_enough((monitor.width * scale).into(), width.into())
+        && close_enough((monitor.height * scale).into(), height.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use niri_ipc::Mode;
+
+    use super::*;
+
+    fn monitor(x: i32, y: i32, width: i32, height: i32) -> MonitorInfo {
+        MonitorInfo {
             x,
             y,
-            ..
-        } = logical;
+            width,
+            height,
+            scale_factor: 1,
+            manufacturer: Some("Acme".to_string()),
+            model: Some("Display 27".to_string()),
+        }
+    }
 
-        // We'll apply the same general calculation as Gdk 3: any fractional component will be
-        // rounded up.
-        let scale = scale.ceil() as i32;
+    fn output(name: &str, x: i32, y: i32, width: u16, height: u16) -> Output {
+        Output {
+            name: name.to_string(),
+            make: "Acme".to_string(),
+            model: "Display 27".to_string(),
+            serial: None,
+            physical_size: None,
+            modes: vec![Mode {
+                width,
+                height,
+                refresh_rate: 60_000,
+                is_preferred: true,
+            }],
+            current_mode: Some(0),
+            is_custom_mode: false,
+            vrr_supported: false,
+            vrr_enabled: false,
+            logical: Some(LogicalOutput {
+                x,
+                y,
+                width: width.into(),
+                height: height.into(),
+                scale: 1.0,
+                transform: Transform::Normal,
+            }),
+        }
+    }
 
-        Self {
-            width: (*width as i32) * scale,
-            height: (*height as i32) * scale,
-            x: (*x) * scale,
-            y: (*y) * scale,
+    fn with_logical(mut output: Output, f: impl FnOnce(&mut LogicalOutput)) -> Output {
+        f(output.logical.as_mut().unwrap());
+        output
+    }
+
+    struct Case {
+        name: &'static str,
+        monitor: MonitorInfo,
+        outputs: Vec<Output>,
+        expected: Option<&'static str>,
+    }
+
+    #[test]
+    fn best_match_cases() {
+        let cases = [
+            Case {
+                name: "position tells identical monitors apart",
+                monitor: monitor(1920, 0, 1920, 1080),
+                outputs: vec![
+                    output("DP-1", 0, 0, 1920, 1080),
+                    output("DP-2", 1920, 0, 1920, 1080),
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "logical size matches when the position doesn't",
+                monitor: monitor(0, 0, 2560, 1440),
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    output("DP-2", 2020, 0, 2560, 1440),
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "logical size only needs to be close",
+                monitor: monitor(0, 0, 1918, 1081),
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    output("DP-2", 2020, 0, 2560, 1440),
+                ],
+                expected: Some("DP-1"),
+            },
+            Case {
+                name: "physical size is scaled by the integer scale factor",
+                monitor: MonitorInfo {
+                    scale_factor: 2,
+                    ..monitor(0, 0, 1920, 1080)
+                },
+                outputs: vec![
+                    output("DP-1", 100, 0, 2560, 1600),
+                    with_logical(output("DP-2", 2020, 0, 3840, 2160), |logical| {
+                        logical.width = 2560;
+                        logical.height = 1440;
+                        logical.scale = 1.5;
+                    }),
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "physical size is swapped for rotated outputs",
+                monitor: MonitorInfo {
+                    scale_factor: 2,
+                    ..monitor(0, 0, 540, 960)
+                },
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    with_logical(output("DP-2", 2020, 0, 1920, 1080), |logical| {
+                        logical.width = 720;
+                        logical.height = 1280;
+                        logical.scale = 1.5;
+                        logical.transform = Transform::_90;
+                    }),
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "manufacturer breaks a tie on geometry",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![
+                    Output {
+                        make: "Other".to_string(),
+                        ..output("DP-1", 100, 0, 1920, 1080)
+                    },
+                    output("DP-2", 2020, 0, 1920, 1080),
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "model breaks a tie on geometry",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    Output {
+                        model: "Display 24".to_string(),
+                        ..output("DP-2", 2020, 0, 1920, 1080)
+                    },
+                ],
+                expected: Some("DP-1"),
+            },
+            Case {
+                name: "missing Gdk names match empty Niri names",
+                monitor: MonitorInfo {
+                    manufacturer: None,
+                    model: None,
+                    ..monitor(0, 0, 1920, 1080)
+                },
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    Output {
+                        make: String::new(),
+                        model: String::new(),
+                        ..output("DP-2", 2020, 0, 1920, 1080)
+                    },
+                ],
+                expected: Some("DP-2"),
+            },
+            Case {
+                name: "position outranks everything below it",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![
+                    Output {
+                        make: "Other".to_string(),
+                        model: "Other".to_string(),
+                        ..output("DP-1", 0, 0, 2560, 1440)
+                    },
+                    output("DP-2", 2560, 0, 1920, 1080),
+                ],
+                expected: Some("DP-1"),
+            },
+            Case {
+                name: "equal best scores are a tie",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![
+                    output("DP-1", 100, 0, 1920, 1080),
+                    output("DP-2", 2020, 0, 1920, 1080),
+                ],
+                expected: None,
+            },
+            Case {
+                name: "names alone don't distinguish outputs",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![output("DP-1", 100, 0, 2560, 1440)],
+                expected: None,
+            },
+            Case {
+                name: "outputs without a logical output never match",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![Output {
+                    logical: None,
+                    ..output("DP-1", 0, 0, 1920, 1080)
+                }],
+                expected: None,
+            },
+            Case {
+                name: "no outputs",
+                monitor: monitor(0, 0, 1920, 1080),
+                outputs: vec![],
+                expected: None,
+            },
+        ];
+
+        for case in cases {
+            let outputs: Vec<_> = case
+                .outputs
+                .into_iter()
+                .map(|output| (output.name.clone(), output))
+                .collect();
+            let actual = best_match(
+                &case.monitor,
+                outputs.iter().map(|(name, output)| (name, output)),
+            );
+            assert_eq!(actual, case.expected, "{}", case.name);
         }
     }
-}
 
-impl PartialEq for Geometry {
-    fn eq(&self, other: &Self) -> bool {
-        // x and y should be the same regardless, but Gdk is apparently... uh, special when it comes
-        // to calculating the width and height of the monitor, so we'll define it as "close enough
-        // is good enough".
-        let x_delta = ((self.width as f64) / (other.width as f64)) - 1.0;
-        let y_delta = ((self.height as f64) / (other.height as f64)) - 1.0;
+    #[test]
+    fn score() {
+        let monitor = monitor(0, 0, 1920, 1080);
+        let cases = [
+            (output("DP-1", 0, 0, 1920, 1080), Score::all()),
+            (
+                output("DP-1", 1, 0, 1920, 1080),
+                Score::GEOMETRY | Score::MANUFACTURER | Score::MODEL,
+            ),
+            (
+                Output {
+                    make: "Other".to_string(),
+                    ..output("DP-1", 0, 0, 1280, 720)
+                },
+                Score::POSITION | Score::MODEL,
+            ),
+            (
+                Output {
+                    model: "Other".to_string(),
+                    ..output("DP-1", 0, 1, 1280, 720)
+                },
+                Score::MANUFACTURER,
+            ),
+        ];
 
-        x_delta.abs() < 0.03 && y_delta.abs() < 0.03 && self.x == other.x && self.y == other.y
+        for (output, expected) in cases {
+            assert_eq!(Score::new(&monitor, &output), expected, "{output:?}");
+        }
     }
 }
@@ -0,0 +1,388 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::LocalBoxFuture;
+use waybar_cffi::gtk::glib;
+
+use crate::{error::Error, niri::Niri};
+
+/// Something that can write a screenshot of a window to a file.
+pub trait Screenshots: Debug + Send + Sync {
+    /// Requests that a screenshot of the given window be written to the given absolute path.
+    ///
+    /// The file may not exist yet when this returns.
+    fn screenshot_window<'a>(
+        &'a self,
+        id: u64,
+        path: &'a Path,
+    ) -> LocalBoxFuture<'a, Result<(), Error>>;
+}
+
+impl Screenshots for Niri {
+    fn screenshot_window<'a>(
+        &'a self,
+        id: u64,
+        path: &'a Path,
+    ) -> LocalBoxFuture<'a, Result<(), Error>> {
+        Box::pin(Niri::screenshot_window(self, id, path))
+    }
+}
+
+/// Window previews, captured on demand using Niri's screenshot-window action.
+///
+/// Niri doesn't give us a way to get a screenshot back over IPC, so we ask it to write one to a
+/// per-window file in our runtime directory and wait for it to show up. The last capture for each
+/// window is kept on disk, which means hovering over a button can show the previous preview
+/// immediately while a fresh one is captured.
+///
+/// Niri also copies every screenshot to the clipboard, so captures are rate limited: a window
+/// isn't captured again until its refresh interval has passed since the last capture started.
+#[derive(Debug, Clone)]
+pub struct Previews {
+    /// When the last capture of each window started.
+    captured: Arc<Mutex<HashMap<u64, Instant>>>,
+    dir: Arc<Dir>,
+    screenshots: Arc<dyn Screenshots>,
+}
+
+impl Previews {
+    pub fn new(screenshots: impl Screenshots + 'static) -> Self {
+        let base = glib::user_runtime_dir().join("niri-taskbar");
+        remove_stale_dirs(&base);
+
+        Self::with_dir(base.join(std::process::id().to_string()), screenshots)
+    }
+
+    fn with_dir(dir: PathBuf, screenshots: impl Screenshots + 'static) -> Self {
+        Self {
+            captured: Default::default(),
+            dir: Arc::new(Dir(dir)),
+            screenshots: Arc::new(screenshots),
+        }
+    }
+
+    /// Returns the most recent preview for the given window, if one has been captured.
+    pub fn cached(&self, id: u64) -> Option<PathBuf> {
+        let path = self.path(id);
+        path.exists().then_some(path)
+    }
+
+    /// Captures a new preview for the given window, unless the last capture started less than the
+    /// given interval ago, returning the path it was written to.
+    ///
+    /// Returns `None` if no capture was needed, either because the cached preview is still fresh or
+    /// because a capture is already in progress.
+    pub async fn refresh(&self, id: u64, interval: Duration) -> Option<Result<PathBuf, Error>> {
+        {
+            let mut captured = self.captured.lock().expect("previews lock");
+            let now = Instant::now();
+            if captured
+                .get(&id)
+                .is_some_and(|started| now.saturating_duration_since(*started) < interval)
+            {
+                return None;
+            }
+            captured.insert(id, now);
+        }
+
+        let result = self.capture(id).await;
+        if result.is_err() {
+            // Let the next hover try again, rather than waiting out the interval.
+            self.captured.lock().expect("previews lock").remove(&id);
+        }
+        Some(result)
+    }
+
+    /// Captures a new preview for the given window, returning the path it was written to.
+    #[tracing::instrument(level = "DEBUG", skip(self), err)]
+    async fn capture(&self, id: u64) -> Result<PathBuf, Error> {
+        static NEXT_CAPTURE: AtomicU64 = AtomicU64::new(0);
+
+        std::fs::create_dir_all(&self.dir.0).map_err(Error::Preview)?;
+
+        // Niri writes the screenshot asynchronously after acknowledging the request, so we write
+        // to a temporary path and wait for it to appear and stop growing. That way, the cached
+        // preview is never a partially written file. Each capture gets its own temporary path,
+        // since the pointer can pass over the same button again before the last capture is done.
+        let capture = NEXT_CAPTURE.fetch_add(1, Ordering::Relaxed);
+        let pending = self.dir.0.join(format!("{id}.{capture}.pending.png"));
+
+        let result = self.write_pending(id, &pending).await;
+        if result.is_err() {
+            let _ = std::fs::remove_file(&pending);
+        }
+        result?;
+
+        let path = self.path(id);
+        std::fs::rename(&pending, &path).map_err(Error::Preview)?;
+        Ok(path)
+    }
+
+    /// Removes any preview for the given window.
+    pub fn forget(&self, id: u64) {
+        self.captured.lock().expect("previews lock").remove(&id);
+        let _ = std::fs::remove_file(self.path(id));
+    }
+
+    async fn write_pending(&self, id: u64, pending: &Path) -> Result<(), Error> {
+        self.screenshots.screenshot_window(id, pending).await?;
+        wait_for(pending).await
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.0.join(format!("{id}.png"))
+    }
+}
+
+/// The directory previews are written to, which is removed along with everything in it once the
+/// last [`Previews`] using it is dropped.
+#[derive(Debug)]
+struct Dir(PathBuf);
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::info!(%e, dir = ?self.0, "cannot remove preview directory");
+            }
+        }
+    }
+}
+
+// Waybar doesn't necessarily unload the module cleanly, so preview directories can be left behind
+// by earlier processes. Each is named after the process that created it, so any whose process is
+// gone can be removed.
+fn remove_stale_dirs(base: &Path) {
+    let Ok(entries) = std::fs::read_dir(base) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name.to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+
+        if pid != std::process::id() && !Path::new("/proc").join(pid.to_string()).exists() {
+            tracing::debug!(pid, "removing stale preview directory");
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+async fn wait_for(path: &Path) -> Result<(), Error> {
+    static INTERVAL: Duration = Duration::from_millis(50);
+    static ATTEMPTS: u32 = 40;
+
+    let mut last_len = None;
+    for _ in 0..ATTEMPTS {
+        let len = std::fs::metadata(path)
+            .ok()
+            .map(|metadata| metadata.len())
+            .filter(|len| *len > 0);
+        if len.is_some() && len == last_len {
+            return Ok(());
+        }
+
+        last_len = len;
+        glib::timeout_future(INTERVAL).await;
+    }
+
+    Err(Error::PreviewTimeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::future::join;
+
+    use super::*;
+
+    /// Writes a fake screenshot to each requested path.
+    #[derive(Debug, Default)]
+    struct FakeScreenshots {
+        requested: Mutex<Vec<PathBuf>>,
+    }
+
+    impl Screenshots for Arc<FakeScreenshots> {
+        fn screenshot_window<'a>(
+            &'a self,
+            id: u64,
+            path: &'a Path,
+        ) -> LocalBoxFuture<'a, Result<(), Error>> {
+            self.requested.lock().unwrap().push(path.to_path_buf());
+            std::fs::write(path, format!("window {id}")).unwrap();
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[derive(Debug)]
+    struct NoScreenshots;
+
+    impl Screenshots for NoScreenshots {
+        fn screenshot_window<'a>(
+            &'a self,
+            _id: u64,
+            _path: &'a Path,
+        ) -> LocalBoxFuture<'a, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        glib::MainContext::new().block_on(future)
+    }
+
+    #[test]
+    fn capture_moves_screenshot_into_cache() {
+        let base = tempfile::tempdir().unwrap();
+        let screenshots = Arc::new(FakeScreenshots::default());
+        let previews = Previews::with_dir(base.path().join("previews"), screenshots.clone());
+
+        assert_eq!(previews.cached(1), None);
+
+        let path = block_on(previews.capture(1)).unwrap();
+        assert_eq!(previews.cached(1), Some(path.clone()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "window 1");
+
+        // Nothing else should be left behind.
+        let files = std::fs::read_dir(base.path().join("previews"))
+            .unwrap()
+            .count();
+        assert_eq!(files, 1);
+
+        previews.forget(1);
+        assert_eq!(previews.cached(1), None);
+    }
+
+    #[test]
+    fn refresh_skips_recent_captures() {
+        let base = tempfile::tempdir().unwrap();
+        let screenshots = Arc::new(FakeScreenshots::default());
+        let previews = Previews::with_dir(base.path().join("previews"), screenshots.clone());
+        let interval = Duration::from_secs(3600);
+
+        assert!(matches!(
+            block_on(previews.refresh(1, interval)),
+            Some(Ok(_))
+        ));
+        assert!(block_on(previews.refresh(1, interval)).is_none());
+        assert!(matches!(
+            block_on(previews.refresh(2, interval)),
+            Some(Ok(_))
+        ));
+        assert_eq!(screenshots.requested.lock().unwrap().len(), 2);
+
+        // Once the interval has passed, or the window's preview is forgotten, it's captured again.
+        assert!(matches!(
+            block_on(previews.refresh(1, Duration::ZERO)),
+            Some(Ok(_))
+        ));
+        previews.forget(2);
+        assert!(matches!(
+            block_on(previews.refresh(2, interval)),
+            Some(Ok(_))
+        ));
+        assert_eq!(screenshots.requested.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn refresh_skips_captures_in_progress() {
+        let base = tempfile::tempdir().unwrap();
+        let screenshots = Arc::new(FakeScreenshots::default());
+        let previews = Previews::with_dir(base.path().join("previews"), screenshots.clone());
+        let interval = Duration::from_secs(3600);
+
+        let (a, b) = block_on(join(
+            previews.refresh(1, interval),
+            previews.refresh(1, interval),
+        ));
+        assert!(matches!(a, Some(Ok(_))));
+        assert!(b.is_none());
+        assert_eq!(screenshots.requested.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refresh_retries_failed_captures() {
+        let base = tempfile::tempdir().unwrap();
+        let previews = Previews::with_dir(base.path().join("previews"), NoScreenshots);
+        let interval = Duration::from_secs(3600);
+
+        assert!(matches!(
+            block_on(previews.refresh(1, interval)),
+            Some(Err(Error::PreviewTimeout))
+        ));
+        assert!(previews.captured.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_captures_use_separate_temporary_files() {
+        let base = tempfile::tempdir().unwrap();
+        let screenshots = Arc::new(FakeScreenshots::default());
+        let previews = Previews::with_dir(base.path().join("previews"), screenshots.clone());
+
+        let (a, b) = block_on(join(previews.capture(1), previews.capture(1)));
+        assert!(a.is_ok());
+        assert!(b.is_ok());
+
+        let requested = screenshots.requested.lock().unwrap();
+        assert_eq!(requested.len(), 2);
+        assert_ne!(requested[0], requested[1]);
+    }
+
+    #[test]
+    fn capture_times_out_if_nothing_is_written() {
+        let base = tempfile::tempdir().unwrap();
+        let previews = Previews::with_dir(base.path().join("previews"), NoScreenshots);
+
+        assert!(matches!(
+            block_on(previews.capture(1)),
+            Err(Error::PreviewTimeout)
+        ));
+        assert_eq!(previews.cached(1), None);
+    }
+
+    #[test]
+    fn dropping_removes_directory() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = base.path().join("previews");
+        let previews = Previews::with_dir(dir.clone(), Arc::new(FakeScreenshots::default()));
+
+        block_on(previews.capture(1)).unwrap();
+        assert!(dir.exists());
+
+        let clone = previews.clone();
+        drop(previews);
+        assert!(dir.exists());
+
+        drop(clone);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn stale_directories_are_removed() {
+        let base = tempfile::tempdir().unwrap();
+        let ours = base.path().join(std::process::id().to_string());
+        // PIDs are capped well below this on Linux.
+        let stale = base.path().join("4294967295");
+        let other = base.path().join("not-a-pid");
+        for dir in [&ours, &stale, &other] {
+            std::fs::create_dir(dir).unwrap();
+        }
+
+        remove_stale_dirs(base.path());
+
+        assert!(ours.exists());
+        assert!(!stale.exists());
+        assert!(other.exists());
+    }
+}
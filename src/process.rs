@@ -63,6 +63,32 @@ impl Process {
     }
 }
 
+/// Returns the given PID followed by each of its ancestors, walking up the process tree until
+/// there are no more parents.
+///
+/// Errors end the walk early, but aren't otherwise fatal: it's possible the process has simply
+/// already exited, so we return whatever we found up to that point.
+#[tracing::instrument(level = "TRACE", ret)]
+pub async fn ancestors(mut pid: i64) -> Vec<i64> {
+    let mut pids = vec![pid];
+
+    loop {
+        match Process::new(pid).await {
+            Ok(Process { ppid: Some(ppid) }) => {
+                pids.push(ppid);
+                pid = ppid;
+            }
+            Ok(Process { ppid: None }) => break,
+            Err(e) => {
+                tracing::info!(pid, %e, "error walking up process tree");
+                break;
+            }
+        }
+    }
+
+    pids
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("malformed /proc/{pid}/stat: insufficient fields")]
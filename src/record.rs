@@ -0,0 +1,203 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        LazyLock,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Error,
+    notify::{LauncherEntryUpdate, RecordedNotification},
+};
+
+/// If set, every event received from Niri and every notification and launcher entry update is
+/// written to the JSON lines file at this path, replacing anything already in it.
+pub const RECORD_ENV: &str = "NIRI_TASKBAR_RECORD";
+
+/// If set, events are replayed from the JSON lines file at this path instead of being received from
+/// Niri and D-Bus.
+pub const REPLAY_ENV: &str = "NIRI_TASKBAR_REPLAY";
+
+/// A single line in a recording.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The time the event was received, in milliseconds since recording started.
+    pub elapsed_ms: u64,
+    pub record: Record,
+}
+
+/// A recorded event.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Record {
+    LauncherEntry(LauncherEntryUpdate),
+    Niri(niri_ipc::Event),
+    Notification(RecordedNotification),
+}
+
+/// Records an event, if recording is enabled.
+///
+/// The record is only built if it's actually going to be written, so callers don't have to pay
+/// for cloning events when recording is disabled. Serialising and writing happen on a background
+/// thread, so this never blocks the main loop on disk I/O.
+pub fn record(record: impl FnOnce() -> Record) {
+    let Some(recorder) = RECORDER.as_ref() else {
+        return;
+    };
+
+    let entry = Entry {
+        elapsed_ms: u64::try_from(recorder.start.elapsed().as_millis()).unwrap_or(u64::MAX),
+        record: record(),
+    };
+
+    // This only fails if the writer has given up, which it will already have logged.
+    let _ = recorder.tx.send(entry);
+}
+
+/// Returns the path of the recording to replay, if replay is enabled.
+pub fn replay_path() -> Option<PathBuf> {
+    std::env::var_os(REPLAY_ENV).map(PathBuf::from)
+}
+
+/// Loads a recording.
+pub fn load(path: &Path) -> Result<Vec<Entry>, Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| Error::Recording {
+        e,
+        path: path.to_path_buf(),
+    })?;
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| Error::RecordingEntry {
+                e,
+                line: i + 1,
+                path: path.to_path_buf(),
+            })
+        })
+        .collect()
+}
+
+struct Recorder {
+    tx: Sender<Entry>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts recording to the given path, replacing anything already there.
+    ///
+    /// Each recording is a single run, so that elapsed times always start from zero and replays
+    /// are timed correctly.
+    fn start(path: &Path) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::Builder::new()
+            .name("niri-taskbar-recorder".to_string())
+            .spawn(move || write_entries(file, rx))?;
+
+        Ok(Self {
+            tx,
+            start: Instant::now(),
+        })
+    }
+}
+
+fn write_entries(file: File, rx: Receiver<Entry>) {
+    let mut writer = BufWriter::new(file);
+
+    // Entries are flushed once the channel has been drained, so bursts of events are written
+    // together, but nothing is left sitting in the buffer if Waybar exits.
+    while let Ok(entry) = rx.recv() {
+        let result = std::iter::once(entry)
+            .chain(rx.try_iter())
+            .try_for_each(|entry| write_entry(&mut writer, &entry))
+            .and_then(|()| writer.flush());
+
+        if let Err(e) = result {
+            tracing::error!(%e, "cannot write event recording; recording stopped");
+            return;
+        }
+    }
+}
+
+fn write_entry(writer: &mut impl Write, entry: &Entry) -> std::io::Result<()> {
+    match serde_json::to_string(entry) {
+        Ok(line) => writeln!(writer, "{line}"),
+        Err(e) => {
+            tracing::warn!(%e, ?entry, "cannot serialise event for recording");
+            Ok(())
+        }
+    }
+}
+
+static RECORDER: LazyLock<Option<Recorder>> = LazyLock::new(|| {
+    let path = PathBuf::from(std::env::var_os(RECORD_ENV)?);
+
+    match Recorder::start(&path) {
+        Ok(recorder) => {
+            tracing::info!(?path, "recording events");
+            Some(recorder)
+        }
+        Err(e) => {
+            tracing::error!(%e, ?path, "cannot start event recording");
+            None
+        }
+    }
+});
+
+#[cfg(test)]
+mod tests {
+    use niri_ipc::Event;
+
+    use super::*;
+
+    fn entry(elapsed_ms: u64, id: u64) -> Entry {
+        Entry {
+            elapsed_ms,
+            record: Record::Niri(Event::WindowClosed { id }),
+        }
+    }
+
+    #[test]
+    fn written_entries_can_be_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+
+        let (tx, rx) = mpsc::channel();
+        for (elapsed_ms, id) in [(0, 1), (5, 2), (5, 3)] {
+            tx.send(entry(elapsed_ms, id)).unwrap();
+        }
+        drop(tx);
+        write_entries(File::create(&path).unwrap(), rx);
+
+        let loaded = load(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry.record {
+                Record::Niri(Event::WindowClosed { id }) => (entry.elapsed_ms, id),
+                record => panic!("unexpected record {record:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, vec![(0, 1), (5, 2), (5, 3)]);
+    }
+
+    #[test]
+    fn starting_replaces_previous_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        std::fs::write(&path, "{\"elapsed_ms\":90000,\"record\":{}}\n").unwrap();
+
+        let recorder = Recorder::start(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        drop(recorder);
+    }
+}
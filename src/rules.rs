@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+
+use itertools::Itertools;
+use regex::Regex;
+use serde::{
+    Deserialize, Deserializer,
+    de::{Error as _, IgnoredAny},
+};
+
+use crate::{config::deserialise_each, niri::Window};
+
+/// An ordered list of window rules.
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl<'de> Deserialize<'de> for Rules {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let values = Vec::<serde_jsonc::Value>::deserialize(de)?;
+        deserialise_each(values, |i| format!("rules[{i}]")).map(Self)
+    }
+}
+
+impl Rules {
+    /// Returns the paths of any keys within the rules that were not recognised.
+    pub fn unknown_keys(&self) -> impl Iterator<Item = String> {
+        self.0.iter().enumerate().flat_map(|(i, rule)| {
+            rule.unknown
+                .keys()
+                .map(move |key| format!("rules[{i}].{key}"))
+        })
+    }
+
+    /// Evaluates every rule against the given window.
+    ///
+    /// Classes from all matching rules are combined. Where more than one matching rule overrides
+    /// the icon or label, the last one wins.
+    pub fn evaluate(&self, window: &Window) -> Outcome<'_> {
+        let mut outcome = Outcome::default();
+
+        for rule in self.0.iter().filter(|rule| rule.matches(window)) {
+            if let Some(class) = &rule.class {
+                outcome.classes.push(class);
+            }
+            if let Some(icon) = &rule.icon {
+                outcome.icon = Some(icon);
+            }
+            if let Some(label) = &rule.label {
+                outcome.label = Some(label);
+            }
+            outcome.hide |= rule.hide;
+        }
+
+        outcome
+    }
+}
+
+/// The combined result of evaluating [`Rules`] against a window.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Outcome<'a> {
+    pub classes: Vec<&'a str>,
+    pub hide: bool,
+    pub icon: Option<&'a str>,
+    pub label: Option<&'a str>,
+}
+
+/// A single window rule.
+///
+/// Every criterion that is set must match for the rule to apply; a rule with no criteria matches
+/// every window.
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    #[serde(default, deserialize_with = "deserialise_regex")]
+    app_id: Option<Regex>,
+    #[serde(default, deserialize_with = "deserialise_glob")]
+    app_id_glob: Option<Regex>,
+    #[serde(default, deserialize_with = "deserialise_regex")]
+    title: Option<Regex>,
+    #[serde(default)]
+    workspace: Option<WorkspaceMatch>,
+    #[serde(default)]
+    output: Option<String>,
+    #[serde(default)]
+    floating: Option<bool>,
+
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
+    hide: bool,
+    #[serde(default)]
+    icon: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+
+    #[serde(flatten)]
+    unknown: BTreeMap<String, IgnoredAny>,
+}
+
+impl Rule {
+    fn matches(&self, window: &Window) -> bool {
+        let app_id = window.app_id.as_deref().unwrap_or_default();
+        let title = window.title.as_deref().unwrap_or_default();
+
+        self.app_id.as_ref().is_none_or(|re| re.is_match(app_id))
+            && self
+                .app_id_glob
+                .as_ref()
+                .is_none_or(|re| re.is_match(app_id))
+            && self.title.as_ref().is_none_or(|re| re.is_match(title))
+            && self
+                .workspace
+                .as_ref()
+                .is_none_or(|workspace| workspace.matches(window))
+            && self
+                .output
+                .as_deref()
+                .is_none_or(|output| window.output() == Some(output))
+            && self
+                .floating
+                .is_none_or(|floating| window.is_floating == floating)
+    }
+}
+
+/// A workspace criterion, which can either be the workspace index or its name.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WorkspaceMatch {
+    Index(u8),
+    Name(String),
+}
+
+impl WorkspaceMatch {
+    fn matches(&self, window: &Window) -> bool {
+        let workspace = window.workspace();
+
+        match self {
+            Self::Index(idx) => workspace.idx == *idx,
+            Self::Name(name) => workspace.name.as_ref() == Some(name),
+        }
+    }
+}
+
+fn deserialise_regex<'de, D>(de: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(de)?
+        .map(|re| Regex::new(&re).map_err(D::Error::custom))
+        .transpose()
+}
+
+fn deserialise_glob<'de, D>(de: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(de)?
+        .map(|glob| Regex::new(&glob_to_regex(&glob)).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Converts a shell-style glob supporting `*` and `?` into an anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let pattern: String = glob
+        .split('*')
+        .map(|part| part.split('?').map(regex::escape).join("."))
+        .join(".*");
+
+    format!("^{pattern}$")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::niri::{
+        Snapshot,
+        testing::{snapshot, window, workspace},
+    };
+
+    use super::*;
+
+    fn rules(json: &str) -> Rules {
+        serde_jsonc::from_str(json).unwrap()
+    }
+
+    // Returns a floating Firefox window on the "web" workspace (index 2 on DP-1), and a tiled
+    // terminal on an unnamed workspace (index 1 on HDMI-A-1).
+    fn windows() -> Snapshot {
+        let mut firefox = window(1, 20, 1);
+        firefox.app_id = Some("org.mozilla.firefox".to_string());
+        firefox.title = Some("Picture-in-Picture".to_string());
+        firefox.is_floating = true;
+
+        let mut terminal = window(2, 10, 1);
+        terminal.app_id = Some("foot".to_string());
+        terminal.title = Some("~/src (main)".to_string());
+
+        let mut web = workspace(20, 2, "DP-1");
+        web.name = Some("web".to_string());
+
+        snapshot(
+            vec![firefox, terminal],
+            vec![workspace(10, 1, "HDMI-A-1"), web],
+        )
+    }
+
+    // Returns the IDs of the windows the single rule in the given list matches.
+    fn matching(json: &str) -> Vec<u64> {
+        let rules = rules(json);
+        windows()
+            .iter()
+            .filter(|window| rules.0[0].matches(window))
+            .map(|window| window.id)
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn glob_escapes_regex_metacharacters() {
+        assert_eq!(glob_to_regex("a.b+c(d)"), r"^a\.b\+c\(d\)$");
+        assert_eq!(glob_to_regex("[x]{1}|^$"), r"^\[x\]\{1\}\|\^\$$");
+
+        let re = Regex::new(&glob_to_regex("org.mozilla.*")).unwrap();
+        assert!(re.is_match("org.mozilla.firefox"));
+        assert!(!re.is_match("orgXmozilla.firefox"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert_eq!(glob_to_regex("*fire?ox*"), "^.*fire.ox.*$");
+
+        let re = Regex::new(&glob_to_regex("fire?ox")).unwrap();
+        assert!(re.is_match("firefox"));
+        assert!(re.is_match("fireFox"));
+        assert!(!re.is_match("fireox"));
+        assert!(!re.is_match("fireefox"));
+    }
+
+    #[test]
+    fn glob_is_anchored() {
+        let re = Regex::new(&glob_to_regex("foot")).unwrap();
+        assert!(re.is_match("foot"));
+        assert!(!re.is_match("footclient"));
+        assert!(!re.is_match("xfoot"));
+    }
+
+    #[test]
+    fn app_id_glob_matches_whole_app_id() {
+        assert_eq!(matching(r#"[{ "app_id_glob": "*firefox" }]"#), vec![1]);
+        assert_eq!(
+            matching(r#"[{ "app_id_glob": "firefox" }]"#),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn app_id_regex_is_unanchored() {
+        assert_eq!(matching(r#"[{ "app_id": "firefox" }]"#), vec![1]);
+        assert_eq!(matching(r#"[{ "app_id": "^foot$" }]"#), vec![2]);
+        assert_eq!(matching(r#"[{ "app_id": "^firefox" }]"#), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn title_regex() {
+        assert_eq!(
+            matching(r#"[{ "title": "^Picture-in-Picture$" }]"#),
+            vec![1]
+        );
+        assert_eq!(matching(r#"[{ "title": "\\(main\\)" }]"#), vec![2]);
+    }
+
+    #[test]
+    fn workspace_output_and_layout() {
+        assert_eq!(matching(r#"[{ "workspace": 2 }]"#), vec![1]);
+        assert_eq!(matching(r#"[{ "workspace": "web" }]"#), vec![1]);
+        assert_eq!(matching(r#"[{ "workspace": "1" }]"#), Vec::<u64>::new());
+        assert_eq!(matching(r#"[{ "output": "HDMI-A-1" }]"#), vec![2]);
+        assert_eq!(matching(r#"[{ "floating": false }]"#), vec![2]);
+    }
+
+    #[test]
+    fn every_criterion_must_match() {
+        assert_eq!(matching(r#"[{}]"#), vec![1, 2]);
+        assert_eq!(
+            matching(r#"[{ "app_id": "firefox", "floating": true }]"#),
+            vec![1]
+        );
+        assert_eq!(
+            matching(r#"[{ "app_id": "firefox", "floating": false }]"#),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn outcome_combines_matching_rules() {
+        let rules = rules(
+            r#"[
+                { "class": "everything", "icon": "first-icon", "label": "first" },
+                { "app_id": "firefox", "class": "browser", "icon": "/browser.svg" },
+                { "app_id": "foot", "class": "terminal", "hide": true },
+                { "floating": true, "label": "pip" }
+            ]"#,
+        );
+        let windows = windows();
+        let window = |id| windows.iter().find(|window| window.id == id).unwrap();
+
+        assert_eq!(
+            rules.evaluate(window(1)),
+            Outcome {
+                classes: vec!["everything", "browser"],
+                hide: false,
+                icon: Some("/browser.svg"),
+                label: Some("pip"),
+            }
+        );
+        assert_eq!(
+            rules.evaluate(window(2)),
+            Outcome {
+                classes: vec!["everything", "terminal"],
+                hide: true,
+                icon: Some("first-icon"),
+                label: Some("first"),
+            }
+        );
+    }
+
+    #[test]
+    fn errors_name_the_rule() {
+        let e = serde_jsonc::from_str::<Rules>(r#"[{}, { "title": "(" }]"#).unwrap_err();
+        assert!(e.to_string().contains("rules[1]"), "{e}");
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let rules = rules(r#"[{}, { "class": "x", "colour": "red" }]"#);
+        assert_eq!(rules.unknown_keys().collect_vec(), vec!["rules[1].colour"]);
+    }
+}
@@ -1,16 +1,31 @@
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
-use async_channel::Sender;
-use futures::{Stream, StreamExt};
-use niri_ipc::Workspace;
-use waybar_cffi::gtk::glib;
+use async_channel::{Receiver, Sender};
+use futures::{
+    Stream, StreamExt,
+    future::{self, Either},
+};
+use waybar_cffi::gtk::{
+    gio::{
+        self, Cancellable, File, FileMonitorEvent, FileMonitorFlags,
+        prelude::{FileExt, FileMonitorExt},
+    },
+    glib,
+};
 
 use crate::{
     config::Config,
-    error::Error,
-    icon,
-    niri::{Niri, Snapshot, WindowStream},
-    notify::{self, EnrichedNotification},
+    control::Command,
+    hint, icon,
+    niri::{self, EventStream, Niri, Snapshot},
+    notify::{self, EnrichedNotification, LauncherEntryUpdate},
+    preview::Previews,
+    record::{self, Record},
 };
 
 /// Global state for the taskbar.
@@ -20,16 +35,55 @@ pub struct State(Arc<Inner>);
 impl State {
     /// Instantiates the global state.
     pub fn new(config: Config) -> Self {
+        let niri = Niri::new();
+
         Self(Arc::new(Inner {
-            config,
+            config: RwLock::new(Arc::new(config)),
             icon_cache: icon::Cache::default(),
-            niri: Niri::new(),
+            previews: Previews::new(niri.clone()),
+            niri,
         }))
     }
 
-    /// Returns the taskbar configuration.
-    pub fn config(&self) -> &Config {
-        &self.0.config
+    /// Returns the current taskbar configuration.
+    ///
+    /// The configuration may be replaced if it was loaded from a file that has since changed, so
+    /// callers shouldn't hold onto the returned value for longer than they need to.
+    pub fn config(&self) -> Arc<Config> {
+        self.0.config.read().expect("config lock").clone()
+    }
+
+    /// Replaces the taskbar configuration.
+    pub fn set_config(&self, config: Config) {
+        *self.0.config.write().expect("config lock") = Arc::new(config);
+    }
+
+    /// Reloads the configuration from the file it was loaded from, returning true if it was
+    /// replaced.
+    ///
+    /// If there's no file, or it can't be loaded, the current configuration is kept.
+    pub async fn reload_config(&self) -> bool {
+        let Some(path) = self.config().config_file().map(Path::to_path_buf) else {
+            tracing::info!("configuration wasn't loaded from a file; nothing to reload");
+            return false;
+        };
+
+        let load_path = path.clone();
+        let config = match gio::spawn_blocking(move || Config::load(&load_path)).await {
+            Ok(Ok(config)) => config,
+            Ok(Err(e)) => {
+                tracing::error!(%e, "cannot reload configuration; keeping the current one");
+                return false;
+            }
+            Err(_) => {
+                tracing::error!("error received from gio while waiting for task");
+                return false;
+            }
+        };
+
+        tracing::info!(?path, "configuration reloaded");
+        self.set_config(config);
+        true
     }
 
     /// Accesses the global icon cache.
@@ -42,69 +96,318 @@ impl State {
         &self.0.niri
     }
 
-    pub fn event_stream(&self) -> Result<impl Stream<Item = Event> + use<>, Error> {
+    /// Accesses the global window preview store.
+    pub fn previews(&self) -> &Previews {
+        &self.0.previews
+    }
+
+    pub fn event_stream(&self) -> impl Stream<Item = Event> + use<> {
         let (tx, rx) = async_channel::unbounded();
 
-        if self.config().notifications_enabled() {
-            glib::spawn_future_local(notify_stream(tx.clone()));
+        if let Some(path) = self.config().config_file() {
+            glib::spawn_future_local(config_stream(tx.clone(), self.clone(), path.to_path_buf()));
         }
 
-        glib::spawn_future_local(window_stream(tx.clone(), self.niri().window_stream()));
+        if let Some(path) = record::replay_path() {
+            // Replayed Niri events still go through an event stream, so they're handled exactly
+            // as they would have been when they were recorded.
+            let (niri_tx, niri_rx) = async_channel::unbounded();
+            glib::spawn_future_local(replay_stream(tx.clone(), niri_tx, path));
+            let events = EventStream::new(niri_rx.map(Ok)).subscribe();
+            glib::spawn_future_local(niri_stream(tx, events));
+        } else {
+            if self.config().notifications_enabled() {
+                glib::spawn_future_local(notify_stream(tx.clone()));
+            }
 
-        // We don't want to send a set of workspaces through until after the window stream has
-        // yielded a window snapshot, and it's easier to defer it here than in the calling code.
-        let mut delay = Some((tx, self.niri().workspace_stream()?));
+            // Only the subscription is kept, so that it closes once the event stream ends.
+            let events = self.niri().event_stream().subscribe();
+            glib::spawn_future_local(niri_stream(tx, events));
+        }
 
-        Ok(async_stream::stream! {
+        async_stream::stream! {
             while let Ok(event) = rx.recv().await {
-                if let Some((tx, stream)) = delay.take() {
-                    if let &Event::Workspaces(_) = &event {
-                        glib::spawn_future_local(workspace_stream(tx, stream));
-                    }
-                }
-
                 yield event;
             }
-        })
+        }
     }
 }
 
 #[derive(Debug)]
 struct Inner {
-    config: Config,
+    config: RwLock<Arc<Config>>,
     icon_cache: icon::Cache,
     niri: Niri,
+    previews: Previews,
 }
 
 pub enum Event {
+    Command(Command),
+    ConfigReloaded,
+    /// Decorations or the need for attention have expired, if the generation is still current.
+    Expired(u64),
+    HintKey(hint::Key),
+    LauncherEntry(LauncherEntryUpdate),
+    MonitorsChanged,
+    Niri(niri::Event),
     Notification(Box<EnrichedNotification>),
-    WindowSnapshot(Snapshot),
-    Workspaces(()),
+}
+
+async fn config_stream(tx: Sender<Event>, state: State, path: PathBuf) {
+    let monitor =
+        match File::for_path(&path).monitor_file(FileMonitorFlags::NONE, Cancellable::NONE) {
+            Ok(monitor) => monitor,
+            Err(e) => {
+                tracing::error!(%e, ?path, "cannot watch configuration file");
+                return;
+            }
+        };
+
+    // Editors tend to write files in several steps, so we'll only reload once the changes are
+    // done, or the file has been replaced wholesale.
+    let (changed_tx, changed_rx) = async_channel::unbounded();
+    monitor.connect_changed(move |_, _, _, event| {
+        if matches!(
+            event,
+            FileMonitorEvent::ChangesDoneHint | FileMonitorEvent::Created
+        ) {
+            let _ = changed_tx.try_send(());
+        }
+    });
+
+    while changed_rx.recv().await.is_ok() {
+        if !state.reload_config().await {
+            continue;
+        }
+
+        if let Err(e) = tx.send(Event::ConfigReloaded).await {
+            tracing::error!(%e, "error sending configuration reload");
+        }
+    }
+
+    // Keep the monitor alive for as long as we're listening to it.
+    drop(monitor);
 }
 
 async fn notify_stream(tx: Sender<Event>) {
     let mut stream = Box::pin(notify::stream());
 
-    while let Some(notification) = stream.next().await {
-        if let Err(e) = tx.send(Event::Notification(Box::new(notification))).await {
+    while let Some(event) = stream.next().await {
+        let event = match event {
+            notify::Event::LauncherEntry(update) => {
+                record::record(|| Record::LauncherEntry(update.clone()));
+                Event::LauncherEntry(update)
+            }
+            notify::Event::Notification(notification) => {
+                record::record(|| Record::Notification((&notification).into()));
+                Event::Notification(Box::new(notification))
+            }
+        };
+
+        if let Err(e) = tx.send(event).await {
             tracing::error!(%e, "error sending notification");
         }
     }
 }
 
-async fn window_stream(tx: Sender<Event>, window_stream: WindowStream) {
-    while let Some(snapshot) = window_stream.next().await {
-        if let Err(e) = tx.send(Event::WindowSnapshot(snapshot)).await {
-            tracing::error!(%e, "error sending window snapshot");
+async fn replay_stream(tx: Sender<Event>, niri_tx: Sender<niri_ipc::Event>, path: PathBuf) {
+    let load_path = path.clone();
+    let entries = match gio::spawn_blocking(move || record::load(&load_path)).await {
+        Ok(Ok(entries)) => entries,
+        Ok(Err(e)) => {
+            tracing::error!(%e, "cannot load recording to replay");
+            return;
+        }
+        Err(_) => {
+            tracing::error!("error received from gio while waiting for task");
+            return;
+        }
+    };
+
+    // Events are replayed with the same timing as they were recorded, since some problems only
+    // show up when events arrive in quick succession.
+    tracing::info!(?path, entries = entries.len(), "replaying recording");
+    let start = Instant::now();
+    for entry in entries.into_iter() {
+        let at = start + Duration::from_millis(entry.elapsed_ms);
+        glib::timeout_future(at.saturating_duration_since(Instant::now())).await;
+
+        let result = match entry.record {
+            Record::LauncherEntry(update) => {
+                tx.send(Event::LauncherEntry(update)).await.map_err(|_| ())
+            }
+            Record::Niri(event) => niri_tx.send(event).await.map_err(|_| ()),
+            Record::Notification(notification) => tx
+                .send(Event::Notification(Box::new(notification.into())))
+                .await
+                .map_err(|_| ()),
+        };
+        if result.is_err() {
+            tracing::error!("error sending replayed event");
+            return;
+        }
+    }
+
+    tracing::info!(?path, "replay finished");
+}
+
+async fn niri_stream(tx: Sender<Event>, rx: Receiver<niri::Event>) {
+    // Niri can send a burst of window changes in quick succession (for example, when several
+    // windows are rearranged at once), so we hold onto each window snapshot until either a frame
+    // has passed or another kind of event arrives, and only pass on the last one. Anything more
+    // frequent than that wouldn't be visible anyway.
+    static COALESCE_INTERVAL: Duration = Duration::from_millis(16);
+
+    let mut pending: Option<(Snapshot, Instant)> = None;
+    loop {
+        let event = match pending.as_ref().map(|(_, deadline)| *deadline) {
+            Some(deadline) => {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                match future::select(pin!(rx.recv()), pin!(glib::timeout_future(wait))).await {
+                    Either::Left((event, _)) => event,
+                    Either::Right(_) => {
+                        if let Some((snapshot, _)) = pending.take() {
+                            send_niri_event(&tx, niri::Event::Windows(snapshot)).await;
+                        }
+                        continue;
+                    }
+                }
+            }
+            None => rx.recv().await,
+        };
+
+        match event {
+            Ok(niri::Event::Windows(snapshot)) => {
+                let deadline = pending
+                    .take()
+                    .map(|(_, deadline)| deadline)
+                    .unwrap_or_else(|| Instant::now() + COALESCE_INTERVAL);
+                pending = Some((snapshot, deadline));
+            }
+            Ok(event) => {
+                // Flush any pending snapshot first, so events are still seen in order.
+                if let Some((snapshot, _)) = pending.take() {
+                    send_niri_event(&tx, niri::Event::Windows(snapshot)).await;
+                }
+                send_niri_event(&tx, event).await;
+            }
+            Err(_) => {
+                // The event stream has ended, but the last snapshot is still the latest state.
+                if let Some((snapshot, _)) = pending.take() {
+                    send_niri_event(&tx, niri::Event::Windows(snapshot)).await;
+                }
+                break;
+            }
         }
     }
 }
 
-async fn workspace_stream(tx: Sender<Event>, workspace_stream: impl Stream<Item = Vec<Workspace>>) {
-    let mut workspace_stream = Box::pin(workspace_stream);
-    while workspace_stream.next().await.is_some() {
-        if let Err(e) = tx.send(Event::Workspaces(())).await {
-            tracing::error!(%e, "error sending workspaces");
+async fn send_niri_event(tx: &Sender<Event>, event: niri::Event) {
+    if let Err(e) = tx.send(Event::Niri(event)).await {
+        tracing::error!(%e, "error sending niri event");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use niri_ipc::Event as NiriEvent;
+
+    use crate::niri::testing::{window, workspace};
+
+    use super::*;
+
+    /// What a test needs to know about each event: the kind of Niri event, and the focused window
+    /// if it's a window snapshot.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Seen {
+        Windows(Option<u64>),
+        Workspaces,
+        Outputs,
+    }
+
+    impl Seen {
+        fn new(event: Event) -> Self {
+            match event {
+                Event::Niri(niri::Event::Windows(snapshot)) => Self::Windows(
+                    snapshot
+                        .iter()
+                        .find(|window| window.is_focused)
+                        .map(|window| window.id),
+                ),
+                Event::Niri(niri::Event::Workspaces(_)) => Self::Workspaces,
+                Event::Niri(niri::Event::Outputs(_)) => Self::Outputs,
+                _ => panic!("unexpected event"),
+            }
+        }
+    }
+
+    // Returns everything received within the given time, and whether the channel closed.
+    async fn received(rx: &Receiver<Event>, within: Duration) -> (Vec<Seen>, bool) {
+        let mut seen = Vec::new();
+        let deadline = glib::timeout_future(within);
+        let mut deadline = pin!(deadline);
+        loop {
+            match future::select(pin!(rx.recv()), deadline.as_mut()).await {
+                Either::Left((Ok(event), _)) => seen.push(Seen::new(event)),
+                Either::Left((Err(_), _)) => return (seen, true),
+                Either::Right(_) => return (seen, false),
+            }
         }
     }
+
+    #[test]
+    fn niri_stream_coalesces_window_snapshots() {
+        glib::MainContext::new().block_on(async {
+            let (raw_tx, raw_rx) = async_channel::unbounded();
+            let (tx, rx) = async_channel::unbounded();
+            let events = EventStream::new(raw_rx.map(Ok)).subscribe();
+            glib::spawn_future_local(niri_stream(tx, events));
+            let send = |event| raw_tx.try_send(event).unwrap();
+            let wait = Duration::from_millis(100);
+
+            // The initial snapshot is followed by workspace and output events, which flush it
+            // straight away.
+            send(NiriEvent::WorkspacesChanged {
+                workspaces: vec![workspace(10, 1, "DP-1")],
+            });
+            send(NiriEvent::WindowsChanged {
+                windows: vec![window(1, 10, 1), window(2, 10, 2)],
+            });
+            assert_eq!(
+                received(&rx, wait).await,
+                (
+                    vec![Seen::Windows(None), Seen::Workspaces, Seen::Outputs],
+                    false
+                )
+            );
+
+            // A burst of window changes within a frame only produces the last snapshot.
+            for id in [1, 2, 1] {
+                send(NiriEvent::WindowFocusChanged { id: Some(id) });
+            }
+            assert_eq!(
+                received(&rx, wait).await,
+                (vec![Seen::Windows(Some(1))], false)
+            );
+
+            // Anything else flushes the pending snapshot first, so the order is preserved.
+            send(NiriEvent::WindowFocusChanged { id: Some(2) });
+            send(NiriEvent::WorkspaceActivated {
+                id: 10,
+                focused: true,
+            });
+            assert_eq!(
+                received(&rx, wait).await,
+                (vec![Seen::Windows(Some(2)), Seen::Workspaces], false)
+            );
+
+            // When the stream ends, the pending snapshot is still the latest state.
+            send(NiriEvent::WindowFocusChanged { id: Some(1) });
+            drop(raw_tx);
+            assert_eq!(
+                received(&rx, wait).await,
+                (vec![Seen::Windows(Some(1))], true)
+            );
+        });
+    }
 }
@@ -0,0 +1,145 @@
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer, de::Error as _};
+use waybar_cffi::gtk::{glib, pango};
+
+use crate::niri::Window;
+
+/// A tooltip template.
+///
+/// Templates are Pango markup with `{field}` placeholders, which are replaced with the escaped
+/// value of the field when rendered. Unknown fields are left as is.
+#[derive(Debug, Clone)]
+pub struct Template(String);
+
+impl Template {
+    /// Creates a template, checking that it's valid Pango markup.
+    ///
+    /// Gtk silently ignores tooltip markup that doesn't parse, so we'd rather find out when the
+    /// configuration is loaded than when the pointer first hovers over a button. Field values are
+    /// always escaped, so if the template parses with every field empty, it'll parse with any
+    /// values.
+    pub fn new(template: String) -> Result<Self, glib::Error> {
+        let template = Self(template);
+        pango::parse_markup(&template.fill(|_| Some(String::new())), '\0')?;
+
+        Ok(template)
+    }
+
+    /// Renders the template for the given window and its pending notification summaries.
+    pub fn render(&self, window: &Window, notifications: &[String]) -> String {
+        self.fill(|name| field(window, notifications, name))
+    }
+
+    // Replaces each field with its escaped value, leaving fields without a value as they are.
+    fn fill(&self, value: impl Fn(&str) -> Option<String>) -> String {
+        static FIELD: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"\{([a-z_]+)\}").expect("tooltip field regex"));
+
+        FIELD
+            .replace_all(&self.0, |captures: &Captures| match value(&captures[1]) {
+                Some(value) => glib::markup_escape_text(&value).to_string(),
+                None => captures[0].to_string(),
+            })
+            .into_owned()
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::new(String::deserialize(de)?)
+            .map_err(|e| D::Error::custom(format!("tooltip is not valid Pango markup: {e}")))
+    }
+}
+
+fn field(window: &Window, notifications: &[String], name: &str) -> Option<String> {
+    let position = window.layout.pos_in_scrolling_layout;
+
+    Some(match name {
+        "title" => window.title.clone().unwrap_or_default(),
+        "app_id" => window.app_id.clone().unwrap_or_default(),
+        "pid" => window.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+        "workspace_idx" => window.workspace().idx.to_string(),
+        "workspace_name" => window.workspace().name.clone().unwrap_or_default(),
+        "output" => window.output().unwrap_or_default().to_string(),
+        "layout" => match window.is_floating {
+            true => "floating",
+            false => "tiled",
+        }
+        .to_string(),
+        "column" => position
+            .map(|(column, _)| column.to_string())
+            .unwrap_or_default(),
+        "tile" => position
+            .map(|(_, tile)| tile.to_string())
+            .unwrap_or_default(),
+        "notifications" => notifications.join("\n"),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::niri::testing::{snapshot, window, workspace};
+
+    use super::*;
+
+    fn render(template: &str, notifications: &[String]) -> String {
+        let mut window = window(1, 10, 2);
+        window.title = Some("<b>Tom & Jerry</b>".to_string());
+        window.app_id = Some("org.example.App".to_string());
+        let windows = snapshot(vec![window], vec![workspace(10, 3, "DP-1")]);
+
+        Template::new(template.to_string())
+            .unwrap()
+            .render(&windows[0], notifications)
+    }
+
+    #[test]
+    fn fields_are_escaped() {
+        assert_eq!(
+            render("<b>{title}</b> ({app_id})", &[]),
+            "<b>&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;</b> (org.example.App)"
+        );
+    }
+
+    #[test]
+    fn fields_are_filled() {
+        assert_eq!(
+            render(
+                "{pid} {workspace_idx} {output} {layout} {column} {tile} {notifications}",
+                &["one".to_string(), "two".to_string()]
+            ),
+            "1001 3 DP-1 tiled 2 1 one\ntwo"
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_left_alone() {
+        assert_eq!(
+            render("{nope} {title}", &[]),
+            "{nope} &lt;b&gt;Tom &amp; Jerry&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn invalid_markup_is_rejected() {
+        assert!(Template::new("<b>{title}</b>".to_string()).is_ok());
+        assert!(Template::new("<b>{title}".to_string()).is_err());
+        assert!(Template::new("<nope>{title}</nope>".to_string()).is_err());
+        assert!(Template::new("Tom & Jerry".to_string()).is_err());
+    }
+
+    #[test]
+    fn invalid_markup_fails_deserialisation() {
+        let e = serde_json::from_str::<Template>(r#""<i>{title}""#).unwrap_err();
+        assert!(
+            e.to_string().contains("tooltip is not valid Pango markup"),
+            "{e}"
+        );
+    }
+}